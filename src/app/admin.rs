@@ -0,0 +1,106 @@
+use crate::app::state::AppState;
+use crate::auth::extractor::bearer_token;
+use crate::auth::revocation::{RevocationList, token_hash};
+use crate::auth::token::decode_token;
+use axum::Json;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::info;
+
+pub const REVOCATIONS_PATH: &str = "/admin/revocations";
+
+/// Entries to add to or remove from the revocation list.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct RevocationChange {
+    #[serde(flatten)]
+    list: RevocationList,
+    tokens: Vec<String>, // Full tokens, stored as their `token_hash`
+}
+
+impl RevocationChange {
+    fn into_list(self) -> Result<RevocationList, String> {
+        let mut list = self.list;
+        // The bad entry is reported by position, so the token does not end up in responses
+        for (index, token) in self.tokens.iter().enumerate() {
+            let sig = decode_token(token)
+                .ok()
+                .and_then(|auth_token| auth_token.sig)
+                .ok_or_else(|| format!("tokens[{}] is not a signed token", index))?;
+            list.token_hashes.insert(token_hash(&sig));
+        }
+        Ok(list)
+    }
+}
+
+/// Lets through requests bearing `revocation.admin_token`.
+pub async fn require_admin_token(
+    State(app_state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let admin_token = &app_state.settings.revocation.admin_token;
+    match bearer_token(request.headers()) {
+        Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub async fn list_revocations(State(app_state): State<Arc<AppState>>) -> Json<RevocationList> {
+    Json(app_state.revocations.list())
+}
+
+pub async fn add_revocations(
+    State(app_state): State<Arc<AppState>>,
+    Json(change): Json<RevocationChange>,
+) -> Response {
+    update_revocations(app_state, change, "add", RevocationList::merge).await
+}
+
+pub async fn remove_revocations(
+    State(app_state): State<Arc<AppState>>,
+    Json(change): Json<RevocationChange>,
+) -> Response {
+    update_revocations(app_state, change, "remove", |list, change| {
+        list.remove(&change)
+    })
+    .await
+}
+
+async fn update_revocations(
+    app_state: Arc<AppState>,
+    change: RevocationChange,
+    action: &str,
+    apply: impl FnOnce(&mut RevocationList, RevocationList) + Send + 'static,
+) -> Response {
+    let change = match change.into_list() {
+        Ok(change) => change,
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    info!(
+        event = "revocations_updated",
+        action = action,
+        users = change.users.len(),
+        token_hashes = change.token_hashes.len(),
+        issued_before = change.issued_before.len(),
+    );
+    // Writes the revocation file, so it stays off the async workers
+    let revocations = app_state.revocations.clone();
+    let result =
+        tokio::task::spawn_blocking(move || revocations.update(|list| apply(list, change))).await;
+    match result {
+        Ok(Ok(list)) => Json(list).into_response(),
+        Ok(Err(err)) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
@@ -0,0 +1,45 @@
+use crate::app::state::AppState;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// Logs every user's `user_rpc_analysis` stats each `user_rpc_log_interval` seconds.
+/// Ticks fall on wall-clock boundaries (e.g. each whole minute for 60), so intervals
+/// line up across users and instances.
+pub async fn run_stats_flusher(app_state: Arc<AppState>) {
+    let period = Duration::from_secs(app_state.settings.log.user_rpc_log_interval.max(1));
+    let start = Instant::now() + until_next_boundary(SystemTime::now(), period);
+    let mut interval = tokio::time::interval_at(start, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        app_state.flush_rpc_method_state();
+    }
+}
+
+fn until_next_boundary(now: SystemTime, period: Duration) -> Duration {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let period_ms = period.as_millis();
+    let elapsed_ms = since_epoch.as_millis() % period_ms;
+    Duration::from_millis((period_ms - elapsed_ms) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_until_next_boundary() {
+        let minute = Duration::from_secs(60);
+        let at = |millis: u64| UNIX_EPOCH + Duration::from_millis(millis);
+        assert_eq!(until_next_boundary(at(120_000), minute), minute);
+        assert_eq!(
+            until_next_boundary(at(125_500), minute),
+            Duration::from_millis(54_500)
+        );
+        assert_eq!(
+            until_next_boundary(at(179_999), minute),
+            Duration::from_millis(1)
+        );
+    }
+}
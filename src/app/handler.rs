@@ -1,20 +1,78 @@
-use crate::app::state::AppState;
-use crate::auth::extractor::VerifiedToken;
+use crate::app::headers::{self, RequestId, X_REQUEST_ID};
+use crate::app::state::{AppState, LimitError};
+use crate::app::{jsonrpc, telemetry};
+use crate::auth::extractor::{AuthRejection, VerifiedToken};
+use crate::auth::token::AuthToken;
+use crate::limits::ceiling::CeilingGuard;
+use crate::upstream::pool::{InFlightGuard, Upstream, UpstreamPool};
+use crate::upstream::retry;
 use axum::Json;
-use axum::extract::State;
-use axum::http::StatusCode;
+use axum::extract::{Extension, State};
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
+use bytes::Bytes;
+use prometheus::Histogram;
 use reqwest::Response;
-use serde_json::Value;
+use serde_json::{Value, json};
 use std::sync::Arc;
 use std::time::Instant;
-use tracing::trace;
+use tracing::field::Empty;
+use tracing::{Instrument, Span, debug_span, trace};
+
+/// Per-request values shared by the forwarding steps below.
+struct RequestContext<'a> {
+    app_state: &'a AppState,
+    auth_token: &'a AuthToken,
+    request_id: &'a str,
+    start_time: Instant,
+}
+
+impl RequestContext<'_> {
+    /// Counts forwarded requests in `sentrix_requests_total`, one per batch element.
+    fn record_requests(&self, rpc_methods: &[&str], status: StatusCode, backend: &str) {
+        for rpc_method in rpc_methods {
+            self.app_state.metrics.record_request(
+                &self.auth_token.user,
+                rpc_method,
+                status,
+                backend,
+            );
+        }
+    }
+}
 
 pub async fn proxy_handler(
     State(app_state): State<Arc<AppState>>,
-    VerifiedToken(auth_token): VerifiedToken,
-    Json(payload): Json<Value>,
-) -> impl IntoResponse {
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    verified: Result<VerifiedToken, AuthRejection>,
+    body: Bytes,
+) -> axum::response::Response {
+    let auth_token = match verified {
+        Ok(VerifiedToken(auth_token)) => auth_token,
+        Err(rejection) => {
+            // Only the ids are read from the body, so the errors can carry them
+            let body = match serde_json::from_slice(&body) {
+                Ok(jsonrpc::ResponseIds::Batch(ids)) => ids
+                    .into_iter()
+                    .map(|id| rejection.error_object(id))
+                    .collect(),
+                Ok(jsonrpc::ResponseIds::Single(id)) => rejection.error_object(id),
+                Err(_) => rejection.error_object(Value::Null),
+            };
+            return (StatusCode::UNAUTHORIZED, Json(body)).into_response();
+        }
+    };
+    let payload: Value = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(_) => {
+            let error = jsonrpc::with_data(
+                jsonrpc::error_object(Value::Null, jsonrpc::PARSE_ERROR, "parse error"),
+                "parse_error",
+                &request_id,
+            );
+            return (StatusCode::BAD_REQUEST, Json(error)).into_response();
+        }
+    };
     #[cfg(debug_assertions)]
     println!(
         "Received request from {}: {}",
@@ -22,67 +80,592 @@ pub async fn proxy_handler(
     );
 
     let start_time = Instant::now();
-    let request_id = uuid::Uuid::new_v4().to_string();
+    let span = Span::current();
+    span.record("user", auth_token.user.as_str());
+    span.record("request_id", request_id.as_str());
     trace!(
         event = "request_received",
         user = auth_token.user,
         request = payload.to_string(),
+        batch_size = payload.as_array().map_or(1, |entries| entries.len()),
         max_qps = auth_token.qps,
         exp = auth_token.exp,
         request_id = request_id
     );
 
-    let rpc_method = payload
-        .get("method")
-        .and_then(|v| v.as_str())
-        .unwrap_or("unknown");
-
-    let response = app_state
-        .http_client
-        .post(&app_state.settings.backend.rpc_url)
-        .json(&payload)
-        .send()
+    app_state.quota.warm_up(&auth_token.user).await;
+    let in_flight = app_state
+        .acquire_in_flight(&auth_token)
+        .instrument(debug_span!("acquire_in_flight"))
         .await;
+    let mut response = match in_flight {
+        // The in-flight slot is held until the upstream response has been read
+        Ok(_in_flight) => {
+            let ctx = RequestContext {
+                app_state: &app_state,
+                auth_token: &auth_token,
+                request_id: &request_id,
+                start_time,
+            };
+            match payload {
+                Value::Array(entries) => handle_batch(&ctx, entries).await,
+                payload => handle_single(&ctx, payload).await,
+            }
+        }
+        Err(err) => limit_response(&payload, &err, &request_id, vec![]),
+    };
+    headers::insert_rate_limit_headers(
+        response.headers_mut(),
+        &app_state.rate_limit_status(&auth_token),
+    );
+    response
+}
+
+/// Answers a whole request with one error: a single error object, or one per element of a
+/// batch (notifications excepted).
+fn reject_all(payload: &Value, error: impl Fn(Value) -> Value) -> Value {
+    match payload {
+        Value::Array(entries) => entries
+            .iter()
+            .filter_map(jsonrpc::response_id)
+            .map(error)
+            .collect(),
+        payload => error(jsonrpc::request_id(payload).unwrap_or(Value::Null)),
+    }
+}
+
+/// `reject_all` as a response, with the error objects of already rejected batch elements appended.
+fn error_response(
+    status: StatusCode,
+    payload: &Value,
+    rejected: Vec<Value>,
+    error: impl Fn(Value) -> Value,
+) -> axum::response::Response {
+    let mut body = reject_all(payload, error);
+    if let Value::Array(errors) = &mut body {
+        errors.extend(rejected);
+    }
+    (status, Json(body)).into_response()
+}
+
+/// Answer to a request refused by one of the limits: 429 for per-user limits,
+/// 503 when the gateway or its upstreams are at capacity.
+fn limit_response(
+    payload: &Value,
+    err: &LimitError,
+    request_id: &str,
+    rejected: Vec<Value>,
+) -> axum::response::Response {
+    let status = match err {
+        LimitError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::TOO_MANY_REQUESTS,
+    };
+    let mut response = error_response(status, payload, rejected, |id| {
+        jsonrpc::with_data(
+            jsonrpc::limit_error_object(id, err),
+            err.reason(),
+            request_id,
+        )
+    });
+    if let Some(retry_after_secs) = err.retry_after_secs() {
+        headers::insert_retry_after(response.headers_mut(), retry_after_secs);
+    }
+    response
+}
+
+async fn handle_single(ctx: &RequestContext<'_>, payload: Value) -> axum::response::Response {
+    let (app_state, auth_token) = (ctx.app_state, ctx.auth_token);
+    let rpc_method = jsonrpc::rpc_method(&payload);
+    let limits = debug_span!("rate_limit", method = rpc_method)
+        .in_scope(|| app_state.check_limits(auth_token, rpc_method));
+    if let Err(err) = limits {
+        return limit_response(&payload, &err, ctx.request_id, vec![]);
+    }
+
+    let pool = app_state.pools.route(rpc_method);
+    let result = forward(ctx, pool, &payload, &[rpc_method], vec![]).await;
+    app_state.update_rpc_method_state(
+        &auth_token.user,
+        rpc_method,
+        ctx.start_time.elapsed().as_secs_f64() * 1000.0,
+    );
+    result
+}
 
+async fn handle_batch(ctx: &RequestContext<'_>, entries: Vec<Value>) -> axum::response::Response {
+    let (app_state, auth_token) = (ctx.app_state, ctx.auth_token);
+    if entries.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(jsonrpc::with_data(
+                jsonrpc::error_object(Value::Null, jsonrpc::INVALID_REQUEST, "empty batch"),
+                "empty_batch",
+                ctx.request_id,
+            )),
+        )
+            .into_response();
+    }
+    let max_batch_size = app_state.settings.app.max_batch_size;
+    if entries.len() > max_batch_size {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(jsonrpc::with_data(
+                jsonrpc::error_object(
+                    Value::Null,
+                    jsonrpc::BATCH_TOO_LARGE,
+                    &format!("batch size exceeds limit of {}", max_batch_size),
+                ),
+                "batch_too_large",
+                ctx.request_id,
+            )),
+        )
+            .into_response();
+    }
+
+    // Each element is validated and counted against the rate limit and quotas on its own.
+    // Rejected elements are answered locally; the rest are forwarded as one batch.
+    let mut forwarded = Vec::with_capacity(entries.len());
+    let mut rejected = Vec::new();
+    let mut limited = false;
+    let mut retry_after_secs = None;
+    let rate_limit_span = debug_span!("rate_limit", batch_size = entries.len());
+    let rate_limit_guard = rate_limit_span.enter();
+    for entry in entries {
+        if !jsonrpc::is_valid_request(&entry) {
+            let id = jsonrpc::request_id(&entry).unwrap_or(Value::Null);
+            rejected.push(jsonrpc::with_data(
+                jsonrpc::error_object(id, jsonrpc::INVALID_REQUEST, "invalid request"),
+                "invalid_request",
+                ctx.request_id,
+            ));
+            continue;
+        }
+        if let Err(err) = app_state.check_limits(auth_token, jsonrpc::rpc_method(&entry)) {
+            limited = true;
+            retry_after_secs = retry_after_secs.max(err.retry_after_secs());
+            // Notifications never receive a response, not even an error
+            if let Some(id) = jsonrpc::request_id(&entry) {
+                rejected.push(jsonrpc::with_data(
+                    jsonrpc::limit_error_object(id, &err),
+                    err.reason(),
+                    ctx.request_id,
+                ));
+            }
+            continue;
+        }
+        forwarded.push(entry);
+    }
+    drop(rate_limit_guard);
+
+    if forwarded.is_empty() {
+        let status = if limited {
+            StatusCode::TOO_MANY_REQUESTS
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+        // A batch of notifications only gets no answer at all, not even an empty array
+        let mut response = if rejected.is_empty() {
+            status.into_response()
+        } else {
+            (status, Json(Value::Array(rejected))).into_response()
+        };
+        if let Some(retry_after_secs) = retry_after_secs {
+            headers::insert_retry_after(response.headers_mut(), retry_after_secs);
+        }
+        return response;
+    }
+
+    let rpc_methods: Vec<String> = forwarded
+        .iter()
+        .map(|entry| jsonrpc::rpc_method(entry).to_string())
+        .collect();
+
+    // Elements routed to different pools are split into one sub-batch per pool
+    let mut groups: Vec<(Arc<UpstreamPool>, Vec<Value>)> = Vec::new();
+    for entry in forwarded {
+        let pool = app_state.pools.route(jsonrpc::rpc_method(&entry));
+        match groups.iter_mut().find(|(p, _)| Arc::ptr_eq(p, pool)) {
+            Some((_, entries)) => entries.push(entry),
+            None => groups.push((pool.clone(), vec![entry])),
+        }
+    }
+
+    let result = if groups.len() == 1 {
+        let (pool, entries) = groups.pop().unwrap();
+        let rpc_methods: Vec<&str> = rpc_methods.iter().map(String::as_str).collect();
+        forward(ctx, &pool, &Value::Array(entries), &rpc_methods, rejected).await
+    } else {
+        forward_split_batch(ctx, groups, rejected).await
+    };
+    let duration = ctx.start_time.elapsed().as_secs_f64() * 1000.0;
+    for rpc_method in &rpc_methods {
+        app_state.update_rpc_method_state(&auth_token.user, rpc_method, duration);
+    }
+    result
+}
+
+/// Forwards each sub-batch to its pool concurrently and merges the answers into one
+/// batch response. Elements of a sub-batch that could not be forwarded get error objects.
+async fn forward_split_batch(
+    ctx: &RequestContext<'_>,
+    groups: Vec<(Arc<UpstreamPool>, Vec<Value>)>,
+    mut rejected: Vec<Value>,
+) -> axum::response::Response {
+    let sends = groups.into_iter().map(|(pool, entries)| async move {
+        let rpc_methods: Vec<&str> = entries.iter().map(jsonrpc::rpc_method).collect();
+        let payload = Value::Array(entries.clone());
+        let response = send_with_retry(ctx, &pool, &payload, &rpc_methods).await;
+        let (status, backend, result) = match response {
+            Ok(resp) => {
+                let (status, backend) = (resp.resp.status(), resp.upstream.label.clone());
+                let responses = resp
+                    .bytes()
+                    .await
+                    .ok()
+                    .and_then(|body| serde_json::from_slice::<Vec<Value>>(&body).ok())
+                    .ok_or(BODY_ERROR);
+                (status, backend, responses)
+            }
+            Err(err) => {
+                let backend = match &err {
+                    ForwardError::Request(_, upstream) => upstream.label.clone(),
+                    _ => String::new(),
+                };
+                err.refund(ctx, &rpc_methods);
+                let failure = err.failure();
+                (failure.status, backend, Err(failure))
+            }
+        };
+        ctx.record_requests(&rpc_methods, status, &backend);
+        (entries, result)
+    });
+
+    let mut responses = Vec::new();
+    for (entries, result) in futures::future::join_all(sends).await {
+        match result {
+            Ok(entry_responses) => responses.extend(entry_responses),
+            Err(failure) => rejected.extend(
+                entries
+                    .iter()
+                    .filter_map(jsonrpc::request_id)
+                    .map(|id| failure.error_object(id, ctx.request_id)),
+            ),
+        }
+    }
+    responses.extend(rejected);
+
+    let result = Json(Value::Array(responses)).into_response();
     trace!(
-        event = "request_forwarded",
-        user = auth_token.user,
-        duration = start_time.elapsed().as_secs_f64() * 1000.0,
-        backend_url = app_state.settings.backend.rpc_url,
-        request_id = request_id
+        event = "response_sent",
+        user = ctx.auth_token.user,
+        result = format!("{:?}", result),
+        duration = ctx.start_time.elapsed().as_secs_f64() * 1000.0,
+        request_id = ctx.request_id
     );
+    result
+}
+
+/// An upstream answer whose body has not been read yet. The upstream's in-flight slot and
+/// ceiling stay taken, and its latency keeps running, until `bytes` has read the body, so
+/// slow, large responses count in full.
+struct UpstreamResponse {
+    resp: Response,
+    upstream: Arc<Upstream>,
+    start: Instant,
+    latency: Histogram, // sentrix_upstream_latency_seconds for the pool and upstream
+    _in_flight: InFlightGuard,
+    _ceiling: CeilingGuard,
+}
+
+impl UpstreamResponse {
+    async fn bytes(self) -> reqwest::Result<Bytes> {
+        let UpstreamResponse {
+            resp,
+            upstream,
+            start,
+            latency,
+            _in_flight,
+            _ceiling,
+        } = self;
+        let body = resp.bytes().instrument(debug_span!("read_body")).await;
+        record_latency(&upstream, start, &latency);
+        body
+    }
+}
+
+fn record_latency(upstream: &Upstream, start: Instant, histogram: &Histogram) {
+    let latency = start.elapsed().as_secs_f64();
+    upstream.record_latency(latency * 1000.0);
+    histogram.observe(latency);
+}
+
+enum ForwardError {
+    NoUpstream,
+    Saturated, // Every candidate upstream is at its ceiling for this priority tier
+    Request(reqwest::Error, Arc<Upstream>), // Failed on the last upstream tried
+}
+
+/// How requests that got no usable upstream answer are reported to the client.
+struct Failure {
+    status: StatusCode,
+    code: i64,
+    message: &'static str,
+    reason: &'static str,
+}
+
+const BODY_ERROR: Failure = Failure {
+    status: StatusCode::BAD_GATEWAY,
+    code: jsonrpc::UPSTREAM_BODY_ERROR,
+    message: "Failed to read response body",
+    reason: "upstream_body_error",
+};
+
+impl Failure {
+    fn error_object(&self, id: Value, request_id: &str) -> Value {
+        jsonrpc::with_data(
+            jsonrpc::error_object(id, self.code, self.message),
+            self.reason,
+            request_id,
+        )
+    }
+
+    fn response(
+        &self,
+        payload: &Value,
+        rejected: Vec<Value>,
+        request_id: &str,
+    ) -> axum::response::Response {
+        error_response(self.status, payload, rejected, |id| {
+            self.error_object(id, request_id)
+        })
+    }
+}
+
+impl ForwardError {
+    /// Gives back what the requests were charged. No upstream answered them, so nothing is
+    /// charged against the quota (upstream 5xx answers are); shed requests also get their
+    /// rate limit credits back, like those shed by the global ceiling.
+    fn refund(&self, ctx: &RequestContext<'_>, rpc_methods: &[&str]) {
+        ctx.app_state.refund_quota(ctx.auth_token, rpc_methods);
+        if !matches!(self, ForwardError::Request(..)) {
+            ctx.app_state.refund_rate_limit(ctx.auth_token, rpc_methods);
+        }
+    }
+
+    fn failure(&self) -> Failure {
+        match self {
+            ForwardError::NoUpstream => Failure {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                code: jsonrpc::NO_UPSTREAM,
+                message: "No upstream available",
+                reason: "no_upstream",
+            },
+            ForwardError::Saturated => Failure {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                code: jsonrpc::SERVER_OVERLOADED,
+                message: "server overloaded",
+                reason: "upstream_saturated",
+            },
+            ForwardError::Request(..) => Failure {
+                status: StatusCode::BAD_GATEWAY,
+                code: jsonrpc::UPSTREAM_ERROR,
+                message: "Failed to forward request",
+                reason: "upstream_request_failed",
+            },
+        }
+    }
+}
+
+async fn forward(
+    ctx: &RequestContext<'_>,
+    pool: &UpstreamPool,
+    payload: &Value,
+    rpc_methods: &[&str],
+    rejected: Vec<Value>,
+) -> axum::response::Response {
+    let response = send_with_retry(ctx, pool, payload, rpc_methods).await;
+    let backend = match &response {
+        Ok(UpstreamResponse { upstream, .. }) | Err(ForwardError::Request(_, upstream)) => {
+            upstream.label.clone()
+        }
+        Err(_) => String::new(),
+    };
+    if let Err(err) = &response {
+        err.refund(ctx, rpc_methods);
+    }
 
     let result = match response {
-        Ok(resp) => build_proxy_response(resp, &request_id).await,
-        Err(_err) => {
+        Ok(resp) => build_proxy_response(resp, payload, ctx.request_id, rejected).await,
+        // Answered like a global ceiling, Retry-After included
+        Err(ForwardError::Saturated) => {
+            limit_response(payload, &LimitError::Overloaded, ctx.request_id, rejected)
+        }
+        Err(err) => {
             #[cfg(debug_assertions)]
-            eprintln!("Proxy error: {}", _err);
-            (
-                StatusCode::BAD_GATEWAY,
-                "Failed to forward request".to_string(),
-            )
-                .into_response()
+            if let ForwardError::Request(err, _) = &err {
+                eprintln!("Proxy error: {}", err);
+            }
+            err.failure().response(payload, rejected, ctx.request_id)
         }
     };
+    ctx.record_requests(rpc_methods, result.status(), &backend);
     trace!(
         event = "response_sent",
-        user = auth_token.user,
+        user = ctx.auth_token.user,
         result = format!("{:?}", result),
-        duration = start_time.elapsed().as_secs_f64() * 1000.0,
-        request_id = request_id
-    );
-    app_state.update_and_log_rpc_method_state(
-        &auth_token.user,
-        rpc_method,
-        start_time.elapsed().as_secs_f64() * 1000.0,
+        duration = ctx.start_time.elapsed().as_secs_f64() * 1000.0,
+        request_id = ctx.request_id
     );
     result
 }
 
-async fn build_proxy_response(resp: Response, request_id: &str) -> axum::response::Response {
-    let status = resp.status();
+/// Posts the payload upstream. Read-only requests that fail or get a 5xx/429 answer are
+/// retried with backoff, preferring an upstream they have not been tried on yet.
+async fn send_with_retry(
+    ctx: &RequestContext<'_>,
+    pool: &UpstreamPool,
+    payload: &Value,
+    rpc_methods: &[&str],
+) -> Result<UpstreamResponse, ForwardError> {
+    let retry_config = &ctx.app_state.settings.backend.retry;
+    let max_attempts = if retry::is_retryable(retry_config, rpc_methods) {
+        retry_config.max_retries + 1
+    } else {
+        1
+    };
+
+    let share = ctx.app_state.priority_share(ctx.auth_token);
+    let mut tried = Vec::new();
+    let mut saturated: Vec<Arc<Upstream>> = Vec::new();
+    let mut attempt = 1;
+    loop {
+        let skip: Vec<Arc<Upstream>> = tried.iter().chain(&saturated).cloned().collect();
+        let upstream = pool.select(&skip).ok_or(ForwardError::NoUpstream)?;
+        // Saturated upstreams are only picked again once nothing else is left
+        if saturated.iter().any(|s| Arc::ptr_eq(s, &upstream)) {
+            ctx.app_state.metrics.record_rejection("upstream_saturated");
+            return Err(ForwardError::Saturated);
+        }
+        let Some(ceiling) = upstream.ceiling.try_enter(share).filter(|_| {
+            upstream
+                .ceiling
+                .try_acquire(rpc_methods.len() as u32, share)
+        }) else {
+            trace!(
+                event = "upstream_saturated",
+                user = ctx.auth_token.user,
+                backend_url = upstream.url,
+                pool = pool.name,
+                request_id = ctx.request_id
+            );
+            saturated.push(upstream);
+            continue;
+        };
+
+        let span = debug_span!(
+            "upstream",
+            otel.kind = "client",
+            backend_url = upstream.url,
+            pool = pool.name,
+            attempt = attempt,
+            http.status_code = Empty,
+        );
+        // Lets the upstream node's own traces join ours
+        let mut upstream_headers = HeaderMap::new();
+        telemetry::inject_context(&span, &mut upstream_headers);
+        if let Ok(request_id) = ctx.request_id.parse() {
+            upstream_headers.insert(X_REQUEST_ID, request_id);
+        }
+
+        let start = Instant::now();
+        let in_flight = upstream.start_request();
+        let response = ctx
+            .app_state
+            .http_client
+            .post(&upstream.url)
+            .headers(upstream_headers)
+            .json(payload)
+            .send()
+            .instrument(span.clone())
+            .await;
+        if let Ok(resp) = &response {
+            span.record("http.status_code", resp.status().as_u16());
+        }
+
+        trace!(
+            event = "request_forwarded",
+            user = ctx.auth_token.user,
+            duration = ctx.start_time.elapsed().as_secs_f64() * 1000.0,
+            backend_url = upstream.url,
+            pool = pool.name,
+            attempt = attempt,
+            request_id = ctx.request_id
+        );
+
+        let resp = match response {
+            Ok(resp) => UpstreamResponse {
+                resp,
+                upstream: upstream.clone(),
+                start,
+                latency: ctx
+                    .app_state
+                    .metrics
+                    .upstream_latency
+                    .with_label_values(&[pool.name.as_str(), upstream.label.as_str()]),
+                _in_flight: in_flight,
+                _ceiling: ceiling,
+            },
+            Err(err) if attempt >= max_attempts => {
+                return Err(ForwardError::Request(err, upstream));
+            }
+            Err(err) => {
+                drop((in_flight, ceiling));
+                retry_after(ctx, attempt, &upstream, err.to_string()).await;
+                tried.push(upstream);
+                attempt += 1;
+                continue;
+            }
+        };
+        if attempt >= max_attempts || !retry::is_retryable_status(resp.resp.status()) {
+            return Ok(resp);
+        }
+        // The body of an answer being retried is not read
+        record_latency(&upstream, start, &resp.latency);
+        let reason = format!("status {}", resp.resp.status());
+        drop(resp);
+        retry_after(ctx, attempt, &upstream, reason).await;
+        tried.push(upstream);
+        attempt += 1;
+    }
+}
+
+/// Waits out the backoff before retry `attempt + 1`.
+async fn retry_after(ctx: &RequestContext<'_>, attempt: u32, upstream: &Upstream, reason: String) {
+    let retry_config = &ctx.app_state.settings.backend.retry;
 
-    let content_type = resp
+    let backoff = retry::backoff(retry_config, attempt);
+    trace!(
+        event = "request_retry",
+        user = ctx.auth_token.user,
+        backend_url = upstream.url,
+        attempt = attempt,
+        reason = reason,
+        backoff_ms = backoff.as_millis() as u64,
+        request_id = ctx.request_id
+    );
+    tokio::time::sleep(backoff).await;
+}
+
+async fn build_proxy_response(
+    resp: UpstreamResponse,
+    payload: &Value,
+    request_id: &str,
+    rejected: Vec<Value>,
+) -> axum::response::Response {
+    let status = resp.resp.status();
+
+    let mut content_type = resp
+        .resp
         .headers()
         .get(reqwest::header::CONTENT_TYPE)
         .and_then(|h| h.to_str().ok())
@@ -90,6 +673,23 @@ async fn build_proxy_response(resp: Response, request_id: &str) -> axum::respons
         .to_string();
     match resp.bytes().await {
         Ok(body) => {
+            // Partially rejected batches get their local error objects appended
+            let body = if rejected.is_empty() {
+                body
+            } else {
+                content_type = "application/json".to_string();
+                jsonrpc::merge_batch_body(&body, rejected, |upstream_error| {
+                    let responses = reject_all(payload, |id| match &upstream_error {
+                        Some(error) => json!({"jsonrpc": "2.0", "id": id, "error": error}),
+                        None => BODY_ERROR.error_object(id, request_id),
+                    });
+                    match responses {
+                        Value::Array(responses) => responses,
+                        response => vec![response],
+                    }
+                })
+                .into()
+            };
             trace!(
                 event = "response_body",
                 body = String::from_utf8_lossy(&body).to_string(),
@@ -109,15 +709,135 @@ async fn build_proxy_response(resp: Response, request_id: &str) -> axum::respons
             #[cfg(debug_assertions)]
             eprintln!("Failed to read response body: {}: {}", status, _err);
 
-            let fallback_body = "Failed to read response body";
             trace!(
                 event = "prepare_response",
-                body = fallback_body,
+                body = BODY_ERROR.message,
                 status = status.to_string(),
                 content_type = content_type,
                 request_id = request_id,
             );
-            (StatusCode::BAD_GATEWAY, fallback_body.to_string()).into_response()
+            BODY_ERROR.response(payload, rejected, request_id)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::router::build_router;
+    use crate::auth::token::generate_token;
+    use crate::config::Settings;
+
+    /// Serves the gateway on a local port; its upstream at 127.0.0.1:1 refuses connections.
+    async fn spawn_gateway() -> String {
+        let settings = Settings::for_test("[backend.retry]\nmax_retries = 0");
+        let app = build_router(Arc::new(AppState::new(&settings)));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    async fn post(url: &str, token: Option<&str>, body: &str) -> (StatusCode, Value) {
+        let mut request = reqwest::Client::new()
+            .post(url)
+            .header("content-type", "application/json")
+            .body(body.to_string());
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await.unwrap();
+        (response.status(), response.json().await.unwrap())
+    }
+
+    /// The `id` and error code of each response in a batch.
+    fn errors(body: &Value) -> Vec<(Value, i64)> {
+        body.as_array()
+            .unwrap()
+            .iter()
+            .map(|response| {
+                (
+                    response["id"].clone(),
+                    response["error"]["code"].as_i64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_auth_rejection_per_element() {
+        let url = spawn_gateway().await;
+        let batch = r#"[
+            {"jsonrpc": "2.0", "id": 1, "method": "getSlot"},
+            {"jsonrpc": "2.0", "method": "getSlot"},
+            {"jsonrpc": "2.0", "id": "b", "method": "getBalance", "params": ["x"]}
+        ]"#;
+        let (status, body) = post(&url, None, batch).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            errors(&body),
+            [
+                (json!(1), jsonrpc::TOKEN_MISSING),
+                (json!("b"), jsonrpc::TOKEN_MISSING)
+            ]
+        );
+
+        // Auth is checked before the body is parsed
+        let (status, body) = post(&url, Some("not-a-token"), "{not json").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["id"], Value::Null);
+        assert_eq!(body["error"]["code"], jsonrpc::TOKEN_INVALID);
+    }
+
+    #[tokio::test]
+    async fn test_parse_error() {
+        let url = spawn_gateway().await;
+        let token = generate_token("test-secret", "alice", 100, 3600);
+        let (status, body) = post(&url, Some(&token), "{not json").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["id"], Value::Null);
+        assert_eq!(body["error"]["code"], jsonrpc::PARSE_ERROR);
+        assert_eq!(body["error"]["data"]["reason"], "parse_error");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_failure_per_element() {
+        let url = spawn_gateway().await;
+        let token = generate_token("test-secret", "alice", 100, 3600);
+        let batch = r#"[
+            {"jsonrpc": "2.0", "id": 1, "method": "getSlot"},
+            {"jsonrpc": "2.0", "id": 2},
+            {"jsonrpc": "2.0", "id": 3, "method": "getBlockHeight"}
+        ]"#;
+        let (status, body) = post(&url, Some(&token), batch).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            errors(&body),
+            [
+                (json!(1), jsonrpc::UPSTREAM_ERROR),
+                (json!(3), jsonrpc::UPSTREAM_ERROR),
+                (json!(2), jsonrpc::INVALID_REQUEST),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejected_notification_batch() {
+        let url = spawn_gateway().await;
+        let token = generate_token("test-secret", "alice", 0, 3600);
+        let batch = r#"[
+            {"jsonrpc": "2.0", "method": "getSlot"},
+            {"jsonrpc": "2.0", "method": "getBlockHeight"}
+        ]"#;
+        let response = reqwest::Client::new()
+            .post(&url)
+            .bearer_auth(token)
+            .header("content-type", "application/json")
+            .body(batch)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.bytes().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+use crate::limits::rate_limit::RateLimitStatus;
+use axum::extract::Request;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// ID correlating a request across our logs, the upstream and the client. Taken from the
+/// client's `X-Request-Id` when valid, generated otherwise.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        match headers.get(X_REQUEST_ID).and_then(|v| v.to_str().ok()) {
+            Some(request_id) if is_valid_request_id(request_id) => {
+                RequestId(request_id.to_string())
+            }
+            _ => RequestId(uuid::Uuid::new_v4().to_string()),
+        }
+    }
+}
+
+/// Up to 128 characters out of letters, digits and `-_.:`, so the ID is safe to log and echo.
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LEN
+        && request_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// Makes the request's `RequestId` available to handlers and echoes it in `X-Request-Id`.
+pub async fn propagate_request_id(mut request: Request, next: Next) -> Response {
+    let request_id = RequestId::from_headers(request.headers());
+    let header = HeaderValue::from_str(&request_id.0).ok();
+    request.extensions_mut().insert(request_id);
+    let mut response = next.run(request).await;
+    if let Some(header) = header {
+        response.headers_mut().insert(X_REQUEST_ID, header);
+    }
+    response
+}
+
+/// Adds the `RateLimit-*` headers and `Retry-After`, unless a more specific
+/// `Retry-After` (e.g. an exhausted quota's reset) was already set.
+pub fn insert_rate_limit_headers(headers: &mut HeaderMap, status: &RateLimitStatus) {
+    headers.insert(RATELIMIT_LIMIT, HeaderValue::from(status.limit));
+    headers.insert(RATELIMIT_REMAINING, HeaderValue::from(status.remaining));
+    headers.insert(RATELIMIT_RESET, HeaderValue::from(status.reset_secs));
+    headers
+        .entry(RETRY_AFTER)
+        .or_insert_with(|| HeaderValue::from(status.retry_after_secs));
+}
+
+pub fn insert_retry_after(headers: &mut HeaderMap, retry_after_secs: u64) {
+    headers.insert(RETRY_AFTER, HeaderValue::from(retry_after_secs));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_id_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            X_REQUEST_ID,
+            HeaderValue::from_static("support-1234:abc_d.e"),
+        );
+        assert_eq!(RequestId::from_headers(&headers).0, "support-1234:abc_d.e");
+
+        // Invalid IDs are replaced rather than echoed
+        for invalid in ["", "has space", "<script>", &"a".repeat(129)] {
+            headers.insert(X_REQUEST_ID, HeaderValue::from_str(invalid).unwrap());
+            let generated = RequestId::from_headers(&headers).0;
+            assert!(uuid::Uuid::parse_str(&generated).is_ok(), "{:?}", invalid);
+        }
+        assert!(uuid::Uuid::parse_str(&RequestId::from_headers(&HeaderMap::new()).0).is_ok());
+    }
+}
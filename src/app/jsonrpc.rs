@@ -0,0 +1,333 @@
+use crate::app::state::LimitError;
+use serde::de::{Deserialize, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde_json::{Value, json};
+use std::fmt;
+
+// Standard JSON-RPC 2.0 error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+
+// Sentrix-specific error codes, within the -32000..=-32099 range JSON-RPC leaves to servers:
+//   -32050..=-32059  authentication
+//   -32060..=-32069  rate limits, quotas and capacity
+//   -32070..=-32079  upstream failures
+//   -32080..=-32089  malformed requests
+pub const TOKEN_MISSING: i64 = -32050;
+pub const TOKEN_INVALID: i64 = -32051;
+pub const TOKEN_EXPIRED: i64 = -32052;
+pub const TOKEN_REVOKED: i64 = -32053;
+pub const RATE_LIMIT_EXCEEDED: i64 = -32060;
+pub const QUOTA_EXCEEDED: i64 = -32061;
+pub const CONCURRENCY_LIMIT_EXCEEDED: i64 = -32062;
+pub const SERVER_OVERLOADED: i64 = -32063;
+pub const SUBSCRIPTION_LIMIT_EXCEEDED: i64 = -32064;
+pub const UPSTREAM_ERROR: i64 = -32070;
+pub const UPSTREAM_BODY_ERROR: i64 = -32071;
+pub const NO_UPSTREAM: i64 = -32072;
+pub const BATCH_TOO_LARGE: i64 = -32080;
+
+/// Returns the method name of a single JSON-RPC request, or "unknown".
+pub fn rpc_method(request: &Value) -> &str {
+    request
+        .get("method")
+        .and_then(|v| v.as_str())
+        .unwrap_or("unknown")
+}
+
+/// Returns the `id` of a request, or `None` if it is a notification.
+pub fn request_id(request: &Value) -> Option<Value> {
+    request.get("id").cloned()
+}
+
+/// The `id` an error response to `request` carries: its own, or null when it has none
+/// because it is malformed. Notifications get no response at all.
+pub fn response_id(request: &Value) -> Option<Value> {
+    match request_id(request) {
+        Some(id) => Some(id),
+        None if is_valid_request(request) => None,
+        None => Some(Value::Null),
+    }
+}
+
+/// A batch element must be an object carrying a string `method`.
+pub fn is_valid_request(request: &Value) -> bool {
+    request.is_object() && request.get("method").is_some_and(|m| m.is_string())
+}
+
+pub fn error_object(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {
+            "code": code,
+            "message": message,
+        }
+    })
+}
+
+/// Adds a machine-readable `reason` and the `request_id` to the `data` of an error object,
+/// so clients can tell failures apart and quote the ID to support.
+pub fn with_data(mut error: Value, reason: &str, request_id: &str) -> Value {
+    if let Some(error) = error.get_mut("error").and_then(Value::as_object_mut)
+        && let Value::Object(data) = error.entry("data").or_insert_with(|| json!({}))
+    {
+        data.insert("reason".to_string(), reason.into());
+        data.insert("request_id".to_string(), request_id.into());
+    }
+    error
+}
+
+/// Error object for a request refused by a per-user limit. Quota errors carry the
+/// exhausted period and when it resets in `data`.
+pub fn limit_error_object(id: Value, error: &LimitError) -> Value {
+    match error {
+        LimitError::RateLimited => error_object(id, RATE_LIMIT_EXCEEDED, &error.to_string()),
+        LimitError::TooManyInFlight => {
+            error_object(id, CONCURRENCY_LIMIT_EXCEEDED, &error.to_string())
+        }
+        LimitError::Overloaded => error_object(id, SERVER_OVERLOADED, &error.to_string()),
+        LimitError::QuotaExceeded(exceeded) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {
+                "code": QUOTA_EXCEEDED,
+                "message": format!("{} quota exceeded", exceeded.period.as_str()),
+                "data": {
+                    "period": exceeded.period.as_str(),
+                    "quota": exceeded.quota,
+                    "used": exceeded.used,
+                    "reset_at": exceeded.reset_at.to_rfc3339(),
+                },
+            }
+        }),
+    }
+}
+
+/// The ids an error response to a request body needs, as `response_id` picks them, read
+/// without building the rest of the body into a `Value`.
+#[derive(Debug, PartialEq)]
+pub enum ResponseIds {
+    Single(Value),
+    Batch(Vec<Value>), // Notifications left out
+}
+
+impl<'de> Deserialize<'de> for ResponseIds {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct IdsVisitor;
+
+        impl<'de> Visitor<'de> for IdsVisitor {
+            type Value = ResponseIds;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a JSON-RPC request or batch")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ResponseIds, A::Error> {
+                let mut ids = Vec::new();
+                while let Some(ElementId(id)) = seq.next_element()? {
+                    ids.extend(id);
+                }
+                Ok(ResponseIds::Batch(ids))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<ResponseIds, A::Error> {
+                let ElementId(id) = ElementIdVisitor.visit_map(map)?;
+                Ok(ResponseIds::Single(id.unwrap_or(Value::Null)))
+            }
+        }
+
+        deserializer.deserialize_any(IdsVisitor)
+    }
+}
+
+/// `response_id` of one request.
+struct ElementId(Option<Value>);
+
+impl<'de> Deserialize<'de> for ElementId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ElementIdVisitor)
+    }
+}
+
+struct ElementIdVisitor;
+
+impl<'de> Visitor<'de> for ElementIdVisitor {
+    type Value = ElementId;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a JSON-RPC request")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<ElementId, A::Error> {
+        let (mut id, mut has_method) = (None, false);
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "id" => id = Some(map.next_value::<Value>()?),
+                "method" => has_method = map.next_value::<Value>()?.is_string(),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(ElementId(match id {
+            Some(id) => Some(id),
+            None if has_method => None,
+            None => Some(Value::Null),
+        }))
+    }
+
+    // Anything but an object is malformed, answered with a null id
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ElementId, A::Error> {
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(ElementId(Some(Value::Null)))
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<ElementId, E> {
+        Ok(ElementId(Some(Value::Null)))
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<ElementId, E> {
+        Ok(ElementId(Some(Value::Null)))
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<ElementId, E> {
+        Ok(ElementId(Some(Value::Null)))
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<ElementId, E> {
+        Ok(ElementId(Some(Value::Null)))
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<ElementId, E> {
+        Ok(ElementId(Some(Value::Null)))
+    }
+
+    fn visit_unit<E>(self) -> Result<ElementId, E> {
+        Ok(ElementId(Some(Value::Null)))
+    }
+}
+
+/// Appends locally generated error objects to an upstream batch response body. When the
+/// body is not a JSON array (a single error object, an HTML error page), `unanswered` is given
+/// the upstream's `error` member, if any, and supplies the responses to the forwarded requests.
+pub fn merge_batch_body(
+    body: &[u8],
+    rejected: Vec<Value>,
+    unanswered: impl FnOnce(Option<Value>) -> Vec<Value>,
+) -> Vec<u8> {
+    let mut responses = match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(responses)) => responses,
+        Ok(mut upstream) => unanswered(upstream.get_mut("error").map(Value::take)),
+        Err(_) => unanswered(None),
+    };
+    responses.extend(rejected);
+    serde_json::to_vec(&responses).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_request() {
+        assert!(is_valid_request(
+            &json!({"jsonrpc": "2.0", "id": 1, "method": "getSlot"})
+        ));
+        assert!(!is_valid_request(&json!({"jsonrpc": "2.0", "id": 1})));
+        assert!(!is_valid_request(&json!({"method": 42})));
+        assert!(!is_valid_request(&json!([1, 2])));
+        assert!(!is_valid_request(&json!("getSlot")));
+    }
+
+    #[test]
+    fn test_merge_batch_body() {
+        let body = br#"[{"jsonrpc":"2.0","id":1,"result":42}]"#;
+        let rejected = vec![error_object(
+            json!(2),
+            RATE_LIMIT_EXCEEDED,
+            "rate limit exceeded",
+        )];
+        let unanswered = |error: Option<Value>| {
+            let error = error.unwrap_or(json!({"code": UPSTREAM_BODY_ERROR}));
+            vec![json!({"jsonrpc": "2.0", "id": 1, "error": error})]
+        };
+        let merged: Value =
+            serde_json::from_slice(&merge_batch_body(body, rejected.clone(), unanswered)).unwrap();
+        assert_eq!(merged.as_array().unwrap().len(), 2);
+        assert_eq!(merged[0]["result"], 42);
+        assert_eq!(merged[1]["error"]["code"], RATE_LIMIT_EXCEEDED);
+
+        // Rejected elements are still answered when the upstream did not send an array
+        let merged: Value = serde_json::from_slice(&merge_batch_body(
+            b"<html>502 Bad Gateway</html>",
+            rejected.clone(),
+            unanswered,
+        ))
+        .unwrap();
+        assert_eq!(merged[0]["error"]["code"], UPSTREAM_BODY_ERROR);
+        assert_eq!(merged[1]["error"]["code"], RATE_LIMIT_EXCEEDED);
+
+        let body = br#"{"jsonrpc":"2.0","id":null,"error":{"code":-32005,"message":"busy"}}"#;
+        let merged: Value =
+            serde_json::from_slice(&merge_batch_body(body, rejected, unanswered)).unwrap();
+        assert_eq!(merged[0]["id"], 1);
+        assert_eq!(merged[0]["error"]["code"], -32005);
+        assert_eq!(merged[1]["error"]["code"], RATE_LIMIT_EXCEEDED);
+    }
+
+    #[test]
+    fn test_with_data() {
+        let error = with_data(
+            error_object(json!(1), INVALID_REQUEST, "invalid request"),
+            "invalid_request",
+            "abc",
+        );
+        assert_eq!(error["error"]["data"]["reason"], "invalid_request");
+        assert_eq!(error["error"]["data"]["request_id"], "abc");
+
+        let quota = json!({"error": {"code": QUOTA_EXCEEDED, "data": {"period": "daily"}}});
+        let quota = with_data(quota, "daily_quota", "abc");
+        assert_eq!(quota["error"]["data"]["period"], "daily");
+        assert_eq!(quota["error"]["data"]["request_id"], "abc");
+    }
+
+    #[test]
+    fn test_response_id() {
+        assert_eq!(
+            response_id(&json!({"id": 7, "method": "getSlot"})),
+            Some(json!(7))
+        );
+        assert_eq!(response_id(&json!({"method": "getSlot"})), None);
+        assert_eq!(response_id(&json!(42)), Some(Value::Null));
+    }
+
+    #[test]
+    fn test_response_ids() {
+        let ids = |body: &str| serde_json::from_str::<ResponseIds>(body).ok();
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "getSlot", "params": [{"commitment": "final"}]},
+            {"method": "getSlot"},
+            {"id": null, "method": 5},
+            {"params": []},
+            42,
+            [1]
+        ]);
+        let expected: Vec<Value> = batch
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(response_id)
+            .collect();
+        assert_eq!(ids(&batch.to_string()), Some(ResponseIds::Batch(expected)));
+        assert_eq!(
+            ids(r#"{"method": "getSlot", "id": "a"}"#),
+            Some(ResponseIds::Single(json!("a")))
+        );
+        assert_eq!(
+            ids(r#"{"method": "getSlot"}"#),
+            Some(ResponseIds::Single(Value::Null))
+        );
+        assert_eq!(ids("42"), None);
+        assert_eq!(ids(r#"[{"id": 1"#), None);
+    }
+}
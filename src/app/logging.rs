@@ -1,8 +1,29 @@
+use crate::app::telemetry::init_tracer_provider;
 use crate::config::Settings;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_sdk::trace::SdkTracerProvider;
 use std::fs::OpenOptions;
+use tracing_subscriber::Layer;
+use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
-pub fn init_logger(settings: &Settings) -> tracing_appender::non_blocking::WorkerGuard {
+/// Keeps the log writer and span exporter running; pending output is flushed on drop.
+pub struct LoggerGuard {
+    _writer: tracing_appender::non_blocking::WorkerGuard,
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for LoggerGuard {
+    fn drop(&mut self) {
+        if let Some(tracer_provider) = &self.tracer_provider {
+            let _ = tracer_provider.shutdown();
+        }
+    }
+}
+
+pub fn init_logger(settings: &Settings) -> LoggerGuard {
     let log_file = OpenOptions::new() // Prepare the log file
         .create(true) // Create the file if it doesn't exist
         .append(true) // Append to the file if it exists
@@ -13,10 +34,27 @@ pub fn init_logger(settings: &Settings) -> tracing_appender::non_blocking::Worke
         });
 
     let (non_blocking_writer, guard) = tracing_appender::non_blocking(log_file);
-    let subscriber = tracing_subscriber::fmt()
+    let level = settings.log.level.parse().unwrap_or(tracing::Level::INFO);
+    let log_layer = tracing_subscriber::fmt::layer()
+        .json()
         .with_writer(non_blocking_writer)
         .with_span_events(FmtSpan::CLOSE)
-        .with_max_level(settings.log.level.parse().unwrap_or(tracing::Level::INFO));
-    subscriber.json().init();
-    guard
+        .with_filter(LevelFilter::from_level(level));
+
+    // Spans are exported independently of the log level; trace-level events are left out
+    let tracer_provider = init_tracer_provider(settings);
+    let otel_layer = tracer_provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("sentrix"))
+            .with_filter(LevelFilter::DEBUG)
+    });
+
+    tracing_subscriber::registry()
+        .with(log_layer)
+        .with(otel_layer)
+        .init();
+    LoggerGuard {
+        _writer: guard,
+        tracer_provider,
+    }
 }
@@ -1,5 +1,13 @@
+mod admin;
+mod flusher;
 pub mod handler;
+pub mod headers;
+pub mod jsonrpc;
 mod logging;
+pub mod metrics;
+mod pubsub;
 mod router;
 pub mod startup;
 pub mod state;
+mod sweeper;
+mod telemetry;
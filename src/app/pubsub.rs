@@ -0,0 +1,447 @@
+use crate::app::headers::{self, RequestId};
+use crate::app::jsonrpc;
+use crate::app::state::AppState;
+use crate::auth::extractor::VerifiedToken;
+use crate::auth::token::AuthToken;
+use axum::Json;
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code};
+use axum::extract::{Extension, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use futures::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_tungstenite::tungstenite::Message as UpstreamMessage;
+use tracing::{info, trace, warn};
+
+pub async fn pubsub_handler(
+    State(app_state): State<Arc<AppState>>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    VerifiedToken(auth_token): VerifiedToken,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let rate_limit_status = app_state.rate_limit_status(&auth_token);
+    let mut response = if app_state.try_open_ws_connection(&auth_token.user) {
+        // Created before the upgrade so the connection slot is released even if it never completes
+        let session = Session {
+            app_state,
+            auth_token,
+            connection_id: uuid::Uuid::new_v4().to_string(),
+            request_id,
+            pending: HashMap::new(),
+            subscriptions: HashSet::new(),
+        };
+        ws.on_upgrade(move |socket| relay(socket, session))
+    } else {
+        app_state.metrics.record_rejection("ws_connection_limit");
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(jsonrpc::with_data(
+                jsonrpc::error_object(
+                    Value::Null,
+                    jsonrpc::CONCURRENCY_LIMIT_EXCEEDED,
+                    "too many websocket connections",
+                ),
+                "ws_connection_limit",
+                &request_id,
+            )),
+        )
+            .into_response()
+    };
+    headers::insert_rate_limit_headers(response.headers_mut(), &rate_limit_status);
+    response
+}
+
+struct PendingRequest {
+    method: String,
+    received_at: Instant,
+    unsubscribe: Option<u64>, // Subscription id targeted by an *Unsubscribe request
+}
+
+/// Tracks one client connection: its in-flight requests and confirmed subscriptions.
+struct Session {
+    app_state: Arc<AppState>,
+    auth_token: AuthToken,
+    connection_id: String,
+    request_id: String, // Of the upgrade request, reported in error data
+    pending: HashMap<String, PendingRequest>, // JSON-RPC id -> request awaiting a response
+    subscriptions: HashSet<u64>,
+}
+
+impl Session {
+    /// Checks and records a client request. Returns an error response to send back
+    /// instead of forwarding the request upstream, if it is rejected.
+    fn on_client_request(&mut self, text: &str) -> Option<Value> {
+        let request: Value = serde_json::from_str(text).unwrap_or(Value::Null);
+        let id = jsonrpc::request_id(&request).unwrap_or(Value::Null);
+        // Batches would carry subscribe requests past the per-request checks below
+        if !jsonrpc::is_valid_request(&request) {
+            return self.reject(
+                jsonrpc::error_object(id, jsonrpc::INVALID_REQUEST, "invalid request"),
+                "invalid_request",
+            );
+        }
+        let method = jsonrpc::rpc_method(&request);
+        // Subscription slots are released when the answer to the request is matched by id
+        if is_subscribe(method) && id.is_null() {
+            return self.reject(
+                jsonrpc::error_object(
+                    id,
+                    jsonrpc::INVALID_REQUEST,
+                    "subscribe requests need an id",
+                ),
+                "missing_id",
+            );
+        }
+        if !id.is_null() && self.pending.contains_key(&id.to_string()) {
+            return self.reject(
+                jsonrpc::error_object(id, jsonrpc::INVALID_REQUEST, "request id already in use"),
+                "duplicate_id",
+            );
+        }
+        if let Err(err) = self.app_state.check_limits(&self.auth_token, method) {
+            return self.reject(jsonrpc::limit_error_object(id, &err), err.reason());
+        }
+
+        trace!(
+            event = "ws_request_received",
+            user = self.auth_token.user,
+            request = text,
+            connection_id = self.connection_id
+        );
+        if is_subscribe(method)
+            && !self
+                .app_state
+                .try_add_ws_subscription(&self.auth_token.user)
+        {
+            self.app_state
+                .metrics
+                .record_rejection("subscription_limit");
+            return self.reject(
+                jsonrpc::error_object(
+                    id,
+                    jsonrpc::SUBSCRIPTION_LIMIT_EXCEEDED,
+                    "subscription limit exceeded",
+                ),
+                "subscription_limit",
+            );
+        }
+
+        if !id.is_null() {
+            let unsubscribe = if method.ends_with("Unsubscribe") {
+                request
+                    .get("params")
+                    .and_then(|params| params.get(0))
+                    .and_then(Value::as_u64)
+            } else {
+                None
+            };
+            self.pending.insert(
+                id.to_string(),
+                PendingRequest {
+                    method: method.to_string(),
+                    received_at: Instant::now(),
+                    unsubscribe,
+                },
+            );
+        }
+        None
+    }
+
+    /// Adds the reason and request id to an error sent back to the client, as over HTTP.
+    fn reject(&self, error: Value, reason: &str) -> Option<Value> {
+        Some(jsonrpc::with_data(error, reason, &self.request_id))
+    }
+
+    /// Matches upstream responses to pending requests; notifications are passed through untouched.
+    fn on_upstream_message(&mut self, text: &str) {
+        let Ok(message) = serde_json::from_str::<Value>(text) else {
+            return;
+        };
+        let Some(request) = message
+            .get("id")
+            .and_then(|id| self.pending.remove(&id.to_string()))
+        else {
+            return;
+        };
+
+        self.app_state.update_rpc_method_state(
+            &self.auth_token.user,
+            &request.method,
+            request.received_at.elapsed().as_secs_f64() * 1000.0,
+        );
+
+        let result = message
+            .get("result")
+            .filter(|_| message.get("error").is_none());
+        if is_subscribe(&request.method) {
+            match result.and_then(Value::as_u64) {
+                Some(subscription) => {
+                    self.subscriptions.insert(subscription);
+                    trace!(
+                        event = "ws_subscribed",
+                        user = self.auth_token.user,
+                        method = request.method,
+                        subscription = subscription,
+                        connection_id = self.connection_id
+                    );
+                }
+                // The slot reserved when the request came in is given back
+                None => self.app_state.remove_ws_subscription(&self.auth_token.user),
+            }
+        } else if let Some(subscription) = request.unsubscribe
+            && result == Some(&Value::Bool(true))
+            && self.subscriptions.remove(&subscription)
+        {
+            self.app_state.remove_ws_subscription(&self.auth_token.user);
+            trace!(
+                event = "ws_unsubscribed",
+                user = self.auth_token.user,
+                method = request.method,
+                subscription = subscription,
+                connection_id = self.connection_id
+            );
+        }
+    }
+
+    /// Confirmed subscriptions plus subscribe requests still awaiting an answer.
+    fn open_subscriptions(&self) -> usize {
+        let pending = self
+            .pending
+            .values()
+            .filter(|request| is_subscribe(&request.method))
+            .count();
+        self.subscriptions.len() + pending
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.app_state
+            .close_ws_connection(&self.auth_token.user, self.open_subscriptions());
+    }
+}
+
+fn is_subscribe(method: &str) -> bool {
+    method.ends_with("Subscribe")
+}
+
+async fn relay(mut client: WebSocket, mut session: Session) {
+    let ws_url = &session.app_state.settings.backend.ws_url;
+    let upstream = match tokio_tungstenite::connect_async(ws_url.as_str()).await {
+        Ok((upstream, _)) => upstream,
+        Err(err) => {
+            warn!(
+                event = "ws_upstream_connect_failed",
+                user = session.auth_token.user,
+                backend_url = ws_url,
+                error = err.to_string(),
+                connection_id = session.connection_id
+            );
+            let _ = client
+                .send(Message::Close(Some(CloseFrame {
+                    code: close_code::ERROR,
+                    reason: "upstream unavailable".into(),
+                })))
+                .await;
+            return;
+        }
+    };
+    let start_time = Instant::now();
+    info!(
+        event = "ws_connection_opened",
+        user = session.auth_token.user,
+        backend_url = ws_url,
+        connection_id = session.connection_id
+    );
+
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut upstream_tx, mut upstream_rx) = upstream.split();
+    let mut revocations = session.app_state.revocations.watch();
+    // The token was checked before the upstream connection was made
+    revocations.mark_changed();
+    loop {
+        tokio::select! {
+            Ok(()) = revocations.changed() => {
+                if session.app_state.revocations.is_revoked(&session.auth_token) {
+                    info!(
+                        event = "ws_token_revoked",
+                        user = session.auth_token.user,
+                        connection_id = session.connection_id
+                    );
+                    let _ = client_tx
+                        .send(Message::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "token revoked".into(),
+                        })))
+                        .await;
+                    break;
+                }
+            },
+            message = client_rx.next() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text.as_str().to_string(),
+                    // Checked like text frames, so JSON-RPC sent as binary cannot skip the limits
+                    Some(Ok(Message::Binary(data))) => match String::from_utf8(data.to_vec()) {
+                        Ok(text) => text,
+                        Err(_) => {
+                            let _ = client_tx
+                                .send(Message::Close(Some(CloseFrame {
+                                    code: close_code::INVALID,
+                                    reason: "binary frames must be UTF-8 JSON-RPC".into(),
+                                })))
+                                .await;
+                            break;
+                        }
+                    },
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                };
+                session.app_state.quota.warm_up(&session.auth_token.user).await;
+                let sent = match session.on_client_request(&text) {
+                    Some(error) => client_tx.send(Message::Text(error.to_string().into())).await.is_ok(),
+                    None => upstream_tx.send(UpstreamMessage::text(text)).await.is_ok(),
+                };
+                if !sent {
+                    break;
+                }
+            },
+            message = upstream_rx.next() => match message {
+                Some(Ok(UpstreamMessage::Text(text))) => {
+                    session.on_upstream_message(text.as_str());
+                    if client_tx.send(Message::Text(text.as_str().into())).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(UpstreamMessage::Binary(data))) => {
+                    if client_tx.send(Message::Binary(data)).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(UpstreamMessage::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    let _ = upstream_tx.close().await;
+    let _ = client_tx.close().await;
+
+    info!(
+        event = "ws_connection_closed",
+        user = session.auth_token.user,
+        duration = start_time.elapsed().as_secs_f64() * 1000.0,
+        subscriptions = session.subscriptions.len(),
+        connection_id = session.connection_id
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use serde_json::json;
+
+    fn session(app_state: &Arc<AppState>) -> Session {
+        let auth_token: AuthToken =
+            serde_json::from_str(r#"{"user":"alice","exp":0,"qps":1000}"#).unwrap();
+        Session {
+            app_state: app_state.clone(),
+            auth_token,
+            connection_id: "test".to_string(),
+            request_id: "req-1".to_string(),
+            pending: HashMap::new(),
+            subscriptions: HashSet::new(),
+        }
+    }
+
+    fn subscriptions(app_state: &AppState) -> usize {
+        app_state
+            .user_ws_state
+            .get("alice")
+            .map_or(0, |usage| usage.subscriptions)
+    }
+
+    fn request(id: Value, method: &str, params: Value) -> String {
+        json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}).to_string()
+    }
+
+    fn error_code(response: Option<Value>) -> Option<i64> {
+        response.and_then(|response| response["error"]["code"].as_i64())
+    }
+
+    #[test]
+    fn test_subscription_accounting() {
+        let app_state = Arc::new(AppState::new(&Settings::for_test(
+            r#"
+            [websocket]
+            max_subscriptions_per_user = 2
+            "#,
+        )));
+        let mut session = session(&app_state);
+
+        // A slot is taken while the subscribe request is pending and kept once confirmed
+        assert!(
+            session
+                .on_client_request(&request(json!(1), "slotSubscribe", json!([])))
+                .is_none()
+        );
+        assert_eq!(subscriptions(&app_state), 1);
+        session.on_upstream_message(r#"{"jsonrpc":"2.0","id":1,"result":7}"#);
+        assert_eq!(subscriptions(&app_state), 1);
+
+        // A failed subscribe gives its slot back
+        session.on_client_request(&request(json!(2), "logsSubscribe", json!([])));
+        assert_eq!(subscriptions(&app_state), 2);
+        session.on_upstream_message(
+            r#"{"jsonrpc":"2.0","id":2,"error":{"code":-32602,"message":"bad"}}"#,
+        );
+        assert_eq!(subscriptions(&app_state), 1);
+
+        // Requests whose answer could not be matched never take a slot
+        let no_id = json!({"jsonrpc": "2.0", "method": "slotSubscribe"}).to_string();
+        assert_eq!(
+            error_code(session.on_client_request(&no_id)),
+            Some(jsonrpc::INVALID_REQUEST)
+        );
+        let null_id = request(Value::Null, "slotSubscribe", json!([]));
+        assert_eq!(
+            error_code(session.on_client_request(&null_id)),
+            Some(jsonrpc::INVALID_REQUEST)
+        );
+        session.on_client_request(&request(json!(3), "rootSubscribe", json!([])));
+        assert_eq!(
+            error_code(session.on_client_request(&request(json!(3), "slotSubscribe", json!([])))),
+            Some(jsonrpc::INVALID_REQUEST)
+        );
+        let batch = json!([{"jsonrpc": "2.0", "id": 4, "method": "slotSubscribe"}]).to_string();
+        assert_eq!(
+            error_code(session.on_client_request(&batch)),
+            Some(jsonrpc::INVALID_REQUEST)
+        );
+        assert_eq!(subscriptions(&app_state), 2);
+
+        // The cap counts pending subscribe requests
+        let rejected = session
+            .on_client_request(&request(json!(5), "slotSubscribe", json!([])))
+            .unwrap();
+        assert_eq!(
+            rejected["error"]["code"],
+            json!(jsonrpc::SUBSCRIPTION_LIMIT_EXCEEDED)
+        );
+        assert_eq!(
+            rejected["error"]["data"],
+            json!({"reason": "subscription_limit", "request_id": "req-1"})
+        );
+
+        // Unsubscribing frees the slot
+        session.on_client_request(&request(json!(6), "slotUnsubscribe", json!([7])));
+        session.on_upstream_message(r#"{"jsonrpc":"2.0","id":6,"result":true}"#);
+        assert_eq!(subscriptions(&app_state), 1);
+
+        // Closing the connection frees the rest, pending ones included
+        drop(session);
+        assert_eq!(subscriptions(&app_state), 0);
+    }
+}
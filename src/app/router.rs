@@ -1,11 +1,40 @@
+use crate::app::admin::{
+    REVOCATIONS_PATH, add_revocations, list_revocations, remove_revocations, require_admin_token,
+};
 use crate::app::handler::proxy_handler;
+use crate::app::headers::propagate_request_id;
+use crate::app::metrics::{METRICS_PATH, metrics_handler};
+use crate::app::pubsub::pubsub_handler;
 use crate::app::state::AppState;
+use crate::app::telemetry::trace_request;
 use axum::Router;
-use axum::routing::post;
+use axum::middleware;
+use axum::routing::{get, post};
 use std::sync::Arc;
 
 pub fn build_router(state: Arc<AppState>) -> Router {
-    Router::new()
-        .route("/", post(proxy_handler))
-        .with_state(state)
+    let mut root = post(proxy_handler).layer(middleware::from_fn(trace_request));
+    if !state.settings.backend.ws_url.is_empty() {
+        root = root.get(pubsub_handler); // WebSocket PubSub upgrade
+    }
+    root = root.layer(middleware::from_fn(propagate_request_id));
+    let metrics = &state.settings.metrics;
+    // Tokens may also be sent as the only path segment, e.g. POST /<token>
+    let mut router = Router::new()
+        .route("/", root.clone())
+        .route("/{token}", root);
+    if metrics.enabled && metrics.port.is_none() {
+        router = router.route(METRICS_PATH, get(metrics_handler));
+    }
+    if !state.settings.revocation.admin_token.is_empty() {
+        let admin = get(list_revocations)
+            .post(add_revocations)
+            .delete(remove_revocations)
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_admin_token,
+            ));
+        router = router.route(REVOCATIONS_PATH, admin);
+    }
+    router.with_state(state)
 }
@@ -1,12 +1,36 @@
+use crate::app::flusher::run_stats_flusher;
 use crate::app::logging::init_logger;
+use crate::app::metrics::serve_metrics;
 use crate::app::router::build_router;
 use crate::app::state::AppState;
+use crate::app::sweeper::run_state_sweeper;
+use crate::auth::revocation::run_revocation_watcher;
 use crate::config::Settings;
+use crate::grpc::proxy::serve_grpc;
+use crate::quota::usage::run_usage_flusher;
+use crate::upstream::health::run_health_checker;
+use chrono::Utc;
 use std::sync::Arc;
+use tracing::warn;
 
 pub async fn run_app(settings: Settings) {
     let _guard = init_logger(&settings);
     let app_state = Arc::new(AppState::new(&settings));
+    tokio::spawn(run_stats_flusher(app_state.clone()));
+    tokio::spawn(run_usage_flusher(app_state.clone()));
+    tokio::spawn(run_state_sweeper(app_state.clone()));
+    if !settings.revocation.file.is_empty() {
+        tokio::spawn(run_revocation_watcher(app_state.clone()));
+    }
+    if settings.backend.health_check.enabled {
+        tokio::spawn(run_health_checker(app_state.clone()));
+    }
+    if !settings.backend.yellowstone_grpc_url.is_empty() {
+        tokio::spawn(serve_grpc(app_state.clone()));
+    }
+    if let Some(port) = settings.metrics.port.filter(|_| settings.metrics.enabled) {
+        tokio::spawn(serve_metrics(app_state.clone(), port));
+    }
     let app = build_router(app_state.clone());
 
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", settings.app.port))
@@ -21,8 +45,40 @@ pub async fn run_app(settings: Settings) {
         settings.app.name, settings.app.port
     );
 
-    axum::serve(listener, app).await.unwrap_or_else(|err| {
-        eprintln!("Error starting app: {}", err);
-        std::process::exit(1);
-    });
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("Error starting app: {}", err);
+            std::process::exit(1);
+        });
+
+    // Write out what the periodic flushers have not yet
+    app_state.flush_rpc_method_state();
+    if let Err(err) = app_state.quota.flush(Utc::now()) {
+        warn!(event = "quota_flush_failed", error = err.to_string());
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
@@ -1,25 +1,173 @@
+use crate::app::metrics::Metrics;
+use crate::auth::revocation::Revocations;
+use crate::auth::token::{AuthToken, Keyring};
 use crate::config::Settings;
+use crate::limits::ceiling::{Ceiling, CeilingGuard};
+use crate::limits::rate_limit::{RateLimitState, RateLimitStatus};
+use crate::quota::store::UsageStore;
+use crate::quota::usage::{QuotaExceeded, QuotaPeriod, QuotaTracker};
+use crate::upstream::routing::PoolRouter;
 use dashmap::DashMap;
+use hdrhistogram::Histogram;
+use prometheus::IntGauge;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tracing::info;
 
 #[derive(Clone)]
 pub struct AppState {
     pub settings: Settings,
     pub http_client: reqwest::Client,
+    pub pools: Arc<PoolRouter>,
     pub user_rate_limit_state: DashMap<String, RateLimitState>,
     pub user_rpc_method_state: DashMap<String, UserRpcMethodState>, // user_id -> RpcMethodState
+    pub user_ws_state: DashMap<String, WsUsage>, // user_id -> open websocket connections and subscriptions
+    pub quota: Arc<QuotaTracker>,
+    pub user_in_flight: DashMap<String, UserSlots>, // user_id -> in-flight slots
+    pub global_ceiling: Arc<Ceiling>,
+    pub metrics: Metrics,
+    pub revocations: Arc<Revocations>,
+    pub keyring: Keyring,
 }
 
+/// A user's in-flight slots. Tokens of the same user may carry different limits, so the
+/// semaphore is resized to the limit of the latest request rather than replaced.
 #[derive(Clone)]
-pub struct RateLimitState {
-    pub request_count: u64,
-    pub last_request_time: Instant,
+pub struct UserSlots {
+    limit: usize,
+    semaphore: Arc<Semaphore>,
+    owed: Arc<AtomicUsize>, // Permits to retire as they are released, when shrunk below those in use
+}
+
+impl UserSlots {
+    fn new(limit: usize) -> Self {
+        UserSlots {
+            limit,
+            semaphore: Arc::new(Semaphore::new(limit)),
+            owed: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn resize(&mut self, limit: usize) {
+        if limit > self.limit {
+            let added = limit - self.limit;
+            let cancelled = self
+                .owed
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |owed| {
+                    Some(owed.saturating_sub(added))
+                })
+                .unwrap()
+                .min(added);
+            self.semaphore.add_permits(added - cancelled);
+        } else {
+            let removed = self.limit - limit;
+            let forgotten = self.semaphore.forget_permits(removed);
+            self.owed.fetch_add(removed - forgotten, Ordering::AcqRel);
+        }
+        self.limit = limit;
+    }
+}
+
+/// One of a user's in-flight slots; goes back to the semaphore on drop unless owed.
+struct UserSlot {
+    permit: Option<OwnedSemaphorePermit>,
+    owed: Arc<AtomicUsize>,
+}
+
+impl Drop for UserSlot {
+    fn drop(&mut self) {
+        let owed = self
+            .owed
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |owed| {
+                owed.checked_sub(1)
+            });
+        if owed.is_ok()
+            && let Some(permit) = self.permit.take()
+        {
+            permit.forget();
+        }
+    }
+}
+
+/// Slots held by a request while it is in flight, released on drop.
+pub struct InFlight {
+    _user: Option<UserSlot>,
+    _global: CeilingGuard,
+    gauge: IntGauge,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// Why a request was refused by one of the per-user limits.
+#[derive(Debug)]
+pub enum LimitError {
+    RateLimited,
+    QuotaExceeded(QuotaExceeded),
+    TooManyInFlight,
+    Overloaded, // A global ceiling is full for the token's priority tier
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::RateLimited => write!(f, "rate limit exceeded"),
+            LimitError::QuotaExceeded(exceeded) => write!(
+                f,
+                "{} quota exceeded, resets at {}",
+                exceeded.period.as_str(),
+                exceeded.reset_at.to_rfc3339()
+            ),
+            LimitError::TooManyInFlight => write!(f, "too many concurrent requests"),
+            LimitError::Overloaded => write!(f, "server overloaded"),
+        }
+    }
+}
+
+impl LimitError {
+    /// Label of the `sentrix_limit_rejections_total` metric.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            LimitError::RateLimited => "rate_limited",
+            LimitError::QuotaExceeded(exceeded) => match exceeded.period {
+                QuotaPeriod::Daily => "daily_quota",
+                QuotaPeriod::Monthly => "monthly_quota",
+            },
+            LimitError::TooManyInFlight => "too_many_in_flight",
+            LimitError::Overloaded => "overloaded",
+        }
+    }
+
+    /// Seconds until retrying can succeed, when known better than from the rate limiter.
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            LimitError::RateLimited => None,
+            LimitError::QuotaExceeded(exceeded) => {
+                let wait = exceeded.reset_at - chrono::Utc::now();
+                Some((wait.num_milliseconds().max(0) as u64).div_ceil(1000))
+            }
+            LimitError::TooManyInFlight | LimitError::Overloaded => Some(1),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct WsUsage {
+    pub connections: usize,
+    pub subscriptions: usize,
 }
+
 #[derive(Clone)]
 pub struct UserRpcMethodState {
     pub user_id: String,
-    pub last_log_time: Instant, // Time when this user state was last written to log
+    pub last_request_time: Instant, // Used to evict idle users
     pub rpc_method_state: DashMap<String, RpcMethodState>, // rpc_method -> RpcMethodState
 }
 
@@ -27,45 +175,61 @@ impl UserRpcMethodState {
     pub fn new(user_id: &str) -> Self {
         UserRpcMethodState {
             user_id: user_id.to_string(),
-            last_log_time: Instant::now(),
+            last_request_time: Instant::now(),
             rpc_method_state: DashMap::new(),
         }
     }
-    
-    pub fn log_if_needed(&mut self, user_rpc_log_interval: u64) {
-        // Log the state of this user
-        let now = Instant::now();
-        if now.duration_since(self.last_log_time).as_secs() > user_rpc_log_interval {
-            // Log the state
-            for pair in self.rpc_method_state.iter() {
-                let method = pair.key();
-                let state = pair.value();
-                info!(
-                    event = "user_rpc_analysis",
-                    user = self.user_id,
-                    method = method,
-                    count = state.request_count,
-                    mean = state.mean_response_time,
-                    max = state.max_response_time,
-                    min = state.min_response_time,
-                    std = state.std_response_time,
-                );
-            }
-            self.last_log_time = now;
-            self.rpc_method_state = DashMap::new();
+
+    /// Writes the pending per-method stats to the log and starts a new interval.
+    pub fn flush(&mut self) {
+        for pair in self.rpc_method_state.iter() {
+            let method = pair.key();
+            let state = pair.value();
+            info!(
+                event = "user_rpc_analysis",
+                user = self.user_id,
+                method = method,
+                count = state.request_count,
+                credits = state.credits,
+                mean = state.mean_response_time,
+                max = state.max_response_time,
+                min = state.min_response_time,
+                std = state.std_response_time,
+                p50 = latency_percentile(&state.latency, 0.5),
+                p90 = latency_percentile(&state.latency, 0.9),
+                p99 = latency_percentile(&state.latency, 0.99),
+                p999 = latency_percentile(&state.latency, 0.999),
+            );
         }
+        self.rpc_method_state = DashMap::new();
     }
 }
 #[derive(Clone, Debug, serde::Serialize)]
 pub struct RpcMethodState {
     pub method: String,
     pub request_count: u64,
+    pub credits: u64, // Rate-limit credits spent on this method
     // All times are in milliseconds
-    pub mean_response_time: f64, 
+    pub mean_response_time: f64,
     pub max_response_time: f64,
     pub min_response_time: f64,
     pub std_response_time: f64,
     pub m2: f64, // For variance calculation
+    #[serde(skip)]
+    pub latency: Histogram<u32>, // In microseconds; mergeable across users
+}
+
+// Latencies above this (60s) are recorded as this value
+const MAX_LATENCY_US: u64 = 60_000_000;
+
+fn new_latency_histogram() -> Histogram<u32> {
+    // Two significant digits keep each histogram around 10 KiB
+    Histogram::new_with_bounds(1, MAX_LATENCY_US, 2).expect("valid histogram bounds")
+}
+
+/// Latency at `quantile` (e.g. 0.99) in milliseconds.
+pub fn latency_percentile(latency: &Histogram<u32>, quantile: f64) -> f64 {
+    latency.value_at_quantile(quantile) as f64 / 1000.0
 }
 
 impl RpcMethodState {
@@ -73,27 +237,30 @@ impl RpcMethodState {
         RpcMethodState {
             method: method.to_string(),
             request_count: 0,
+            credits: 0,
             mean_response_time: 0.0,
             max_response_time: 0.0,
             min_response_time: f64::MAX,
             std_response_time: 0.0,
             m2: 0.0,
+            latency: new_latency_histogram(),
         }
     }
-    
-    pub fn update(&mut self, response_time: f64) {
+
+    pub fn update(&mut self, response_time: f64, cost: u32) {
         self.request_count += 1;
+        self.credits += cost as u64;
         if response_time > self.max_response_time {
             self.max_response_time = response_time;
         }
         if response_time < self.min_response_time {
             self.min_response_time = response_time;
         }
-        
+
         // Using Welford's algorithm for online mean and variance calculation
         let delta = response_time - self.mean_response_time;
         self.mean_response_time += delta / self.request_count as f64;
-        
+
         let delta2 = response_time - self.mean_response_time;
         self.m2 += delta * delta2;
         if self.request_count <= 1 {
@@ -101,6 +268,8 @@ impl RpcMethodState {
         } else {
             self.std_response_time = (self.m2 / (self.request_count - 1) as f64).sqrt();
         }
+        self.latency
+            .saturating_record((response_time * 1000.0) as u64);
     }
 }
 
@@ -121,49 +290,193 @@ impl AppState {
                 std::process::exit(1);
             });
 
+        let pools = PoolRouter::new(&settings.backend).unwrap_or_else(|err| {
+            eprintln!("Error configuring backend pools: {}", err);
+            std::process::exit(1);
+        });
+
+        let usage_store = UsageStore::open(&settings.quota.db_path).unwrap_or_else(|err| {
+            eprintln!("Error opening quota usage store: {}", err);
+            std::process::exit(1);
+        });
+
+        let revocations = Revocations::open(&settings.revocation.file).unwrap_or_else(|err| {
+            eprintln!("Error loading revocation list: {}", err);
+            std::process::exit(1);
+        });
+
+        let keyring =
+            Keyring::new(&settings.app.secret_key, &settings.auth).unwrap_or_else(|err| {
+                eprintln!("Error loading signing keys: {}", err);
+                std::process::exit(1);
+            });
+
         AppState {
             settings: settings.clone(),
             http_client,
+            pools: Arc::new(pools),
             user_rate_limit_state: DashMap::new(),
             user_rpc_method_state: DashMap::new(),
+            user_ws_state: DashMap::new(),
+            quota: Arc::new(QuotaTracker::new(usage_store)),
+            user_in_flight: DashMap::new(),
+            global_ceiling: Arc::new(Ceiling::new(
+                settings.limits.global_qps,
+                settings.limits.global_max_in_flight,
+            )),
+            metrics: Metrics::new(settings.metrics.per_user),
+            revocations: Arc::new(revocations),
+            keyring,
         }
     }
 
-    pub fn update_and_check_rate_limit(&self, user_id: &str, user_qps: u32) -> bool {
+    /// Charges the method's credit cost; `user_qps` is in credits per second and
+    /// burst defaults to `user_qps`, i.e. a full second's worth of credits.
+    pub fn update_and_check_rate_limit(
+        &self,
+        user_id: &str,
+        rpc_method: &str,
+        user_qps: u32,
+        burst: Option<u32>,
+    ) -> bool {
         let now = Instant::now();
-        if let Some(rate_limit_state) = self.user_rate_limit_state.get(user_id) {
-            let elapsed = now.duration_since(rate_limit_state.last_request_time);
-            if elapsed <= Duration::from_secs(1)
-                && rate_limit_state.request_count + 1 > user_qps as u64
-            {
-                return false; // Rate limit exceeded
-            }
+        let cost = self.settings.credits.cost(rpc_method);
+        self.user_rate_limit_state
+            .entry(user_id.to_string())
+            .or_insert_with(|| RateLimitState::new(now))
+            .try_acquire(now, cost, user_qps, burst.unwrap_or(user_qps))
+    }
+
+    pub fn rate_limit_status(&self, auth_token: &AuthToken) -> RateLimitStatus {
+        let now = Instant::now();
+        let burst = auth_token.burst.unwrap_or(auth_token.qps);
+        match self.user_rate_limit_state.get(&auth_token.user) {
+            Some(state) => state.status(now, auth_token.qps, burst),
+            None => RateLimitState::new(now).status(now, auth_token.qps, burst),
         }
+    }
 
-        let mut rate_limit_state = self
-            .user_rate_limit_state
-            .entry(user_id.to_string())
-            .or_insert(RateLimitState {
-                request_count: 0,
-                last_request_time: now,
-            });
-        let elapsed = now.duration_since(rate_limit_state.last_request_time);
-        if elapsed >= Duration::from_secs(1) {
-            rate_limit_state.request_count = 1;
-            rate_limit_state.last_request_time = now;
-            true
-        } else {
-            rate_limit_state.request_count += 1;
-            rate_limit_state.request_count <= user_qps as u64
+    /// Applies every per-user limit to one request, charging the method's credit cost.
+    pub fn check_limits(&self, auth_token: &AuthToken, rpc_method: &str) -> Result<(), LimitError> {
+        let result = self.apply_limits(auth_token, rpc_method);
+        if let Err(err) = &result {
+            self.metrics.record_rejection(err.reason());
         }
+        result
     }
-    
-    pub fn update_and_log_rpc_method_state(
-        &self,
-        user_id: &str,
-        rpc_method: &str,
-        response_time: f64,
-    ) {
+
+    fn apply_limits(&self, auth_token: &AuthToken, rpc_method: &str) -> Result<(), LimitError> {
+        if !self.update_and_check_rate_limit(
+            &auth_token.user,
+            rpc_method,
+            auth_token.qps,
+            auth_token.burst,
+        ) {
+            return Err(LimitError::RateLimited);
+        }
+        // A request turned away by a later limit is not charged by the earlier ones
+        if let Err(err) = self.quota.consume(
+            &auth_token.user,
+            self.settings.credits.cost(rpc_method),
+            auth_token.daily_quota,
+            auth_token.monthly_quota,
+        ) {
+            self.refund_rate_limit(auth_token, &[rpc_method]);
+            return Err(LimitError::QuotaExceeded(err));
+        }
+        if !self
+            .global_ceiling
+            .try_acquire(1, self.priority_share(auth_token))
+        {
+            self.refund_rate_limit(auth_token, &[rpc_method]);
+            self.refund_quota(auth_token, &[rpc_method]);
+            return Err(LimitError::Overloaded);
+        }
+        Ok(())
+    }
+
+    /// Returns the rate limit credits of requests that were shed, which is not the user's doing.
+    pub fn refund_rate_limit(&self, auth_token: &AuthToken, rpc_methods: &[&str]) {
+        if let Some(mut state) = self.user_rate_limit_state.get_mut(&auth_token.user) {
+            let cost = rpc_methods
+                .iter()
+                .map(|rpc_method| self.settings.credits.cost(rpc_method))
+                .sum();
+            let burst = auth_token.burst.unwrap_or(auth_token.qps);
+            state.refund(Instant::now(), cost, auth_token.qps, burst);
+        }
+    }
+
+    /// Returns the quota credits of requests that were charged but got no upstream answer.
+    pub fn refund_quota(&self, auth_token: &AuthToken, rpc_methods: &[&str]) {
+        let cost = rpc_methods
+            .iter()
+            .map(|rpc_method| self.settings.credits.cost(rpc_method) as u64)
+            .sum();
+        self.quota.refund(&auth_token.user, cost);
+    }
+
+    /// Share of the global and per-upstream ceilings the token's priority tier may use.
+    pub fn priority_share(&self, auth_token: &AuthToken) -> f64 {
+        let limits = &self.settings.limits;
+        limits
+            .priority_share
+            .get(auth_token.priority.unwrap_or(limits.default_priority))
+    }
+
+    /// Takes one of the user's in-flight slots, waiting up to `queue_timeout_ms` for one
+    /// to free up, then a slot under the global concurrency ceiling.
+    pub async fn acquire_in_flight(&self, auth_token: &AuthToken) -> Result<InFlight, LimitError> {
+        let limits = &self.settings.limits;
+        let user = match auth_token
+            .max_in_flight
+            .map(|limit| limit as usize)
+            .or(limits.max_in_flight_per_user)
+        {
+            Some(limit) => {
+                let (semaphore, owed) = {
+                    let mut slots = self
+                        .user_in_flight
+                        .entry(auth_token.user.clone())
+                        .or_insert_with(|| UserSlots::new(limit));
+                    if slots.limit != limit {
+                        slots.resize(limit);
+                    }
+                    (slots.semaphore.clone(), slots.owed.clone())
+                };
+                let queue_timeout = Duration::from_millis(limits.queue_timeout_ms);
+                match tokio::time::timeout(queue_timeout, semaphore.acquire_owned()).await {
+                    Ok(Ok(permit)) => Some(UserSlot {
+                        permit: Some(permit),
+                        owed,
+                    }),
+                    _ => {
+                        self.metrics
+                            .record_rejection(LimitError::TooManyInFlight.reason());
+                        return Err(LimitError::TooManyInFlight);
+                    }
+                }
+            }
+            None => None,
+        };
+        let Some(global) = self
+            .global_ceiling
+            .try_enter(self.priority_share(auth_token))
+        else {
+            self.metrics
+                .record_rejection(LimitError::Overloaded.reason());
+            return Err(LimitError::Overloaded);
+        };
+        let gauge = self.metrics.in_flight.clone();
+        gauge.inc();
+        Ok(InFlight {
+            _user: user,
+            _global: global,
+            gauge,
+        })
+    }
+
+    pub fn update_rpc_method_state(&self, user_id: &str, rpc_method: &str, response_time: f64) {
         let mut user_rpc_method_state = self
             .user_rpc_method_state
             .entry(user_id.to_string())
@@ -175,8 +488,263 @@ impl AppState {
                 .entry(rpc_method.to_string())
                 .or_insert(RpcMethodState::new(rpc_method));
 
-            rpc_method_state.update(response_time);
+            rpc_method_state.update(response_time, self.settings.credits.cost(rpc_method));
+        }
+        user_rpc_method_state.last_request_time = Instant::now();
+        self.metrics
+            .request_duration
+            .with_label_values(&[rpc_method])
+            .observe(response_time / 1000.0);
+    }
+
+    /// Logs every user's pending per-method stats, followed by each method's
+    /// latency percentiles across all users.
+    pub fn flush_rpc_method_state(&self) {
+        let mut methods: HashMap<String, Histogram<u32>> = HashMap::new();
+        for mut user_rpc_method_state in self.user_rpc_method_state.iter_mut() {
+            for pair in user_rpc_method_state.rpc_method_state.iter() {
+                match methods.get_mut(pair.key()) {
+                    Some(latency) => {
+                        let _ = latency.add(&pair.value().latency);
+                    }
+                    None => {
+                        methods.insert(pair.key().clone(), pair.value().latency.clone());
+                    }
+                }
+            }
+            user_rpc_method_state.flush();
+        }
+        for (method, latency) in methods {
+            info!(
+                event = "rpc_method_analysis",
+                method = method,
+                count = latency.len(),
+                max = latency.max() as f64 / 1000.0,
+                p50 = latency_percentile(&latency, 0.5),
+                p90 = latency_percentile(&latency, 0.9),
+                p99 = latency_percentile(&latency, 0.99),
+                p999 = latency_percentile(&latency, 0.999),
+            );
+        }
+    }
+
+    /// Drops per-user state of users idle for longer than `idle_ttl`, logging their pending
+    /// stats first. Returns the number of users evicted.
+    pub fn evict_idle_users(&self, idle_ttl: Duration) -> usize {
+        let now = Instant::now();
+        let mut evicted = HashSet::new();
+        // An idle user's bucket has long refilled, so a fresh state behaves the same
+        self.user_rate_limit_state.retain(|user_id, state| {
+            let idle = now.saturating_duration_since(state.theoretical_arrival_time) > idle_ttl;
+            if idle {
+                evicted.insert(user_id.clone());
+            }
+            !idle
+        });
+        self.user_rpc_method_state.retain(|user_id, state| {
+            let idle = now.saturating_duration_since(state.last_request_time) > idle_ttl;
+            if idle {
+                state.flush();
+                evicted.insert(user_id.clone());
+            }
+            !idle
+        });
+        self.user_ws_state
+            .retain(|_, usage| usage.connections > 0 || usage.subscriptions > 0);
+        // Semaphores with no permit handed out
+        self.user_in_flight.retain(|_, slots| {
+            Arc::strong_count(&slots.semaphore) > 1
+                || slots.semaphore.available_permits() < slots.limit
+        });
+        evicted.len()
+    }
+
+    /// Number of distinct users with rate-limit or stats state in memory.
+    pub fn tracked_users(&self) -> usize {
+        let mut users: HashSet<String> = self
+            .user_rate_limit_state
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        users.extend(
+            self.user_rpc_method_state
+                .iter()
+                .map(|entry| entry.key().clone()),
+        );
+        users.len()
+    }
+
+    pub fn try_open_ws_connection(&self, user_id: &str) -> bool {
+        let max_connections = self.settings.websocket.max_connections_per_user;
+        let mut usage = self.user_ws_state.entry(user_id.to_string()).or_default();
+        if usage.connections >= max_connections {
+            return false;
+        }
+        usage.connections += 1;
+        true
+    }
+
+    pub fn close_ws_connection(&self, user_id: &str, open_subscriptions: usize) {
+        if let Some(mut usage) = self.user_ws_state.get_mut(user_id) {
+            usage.connections = usage.connections.saturating_sub(1);
+            usage.subscriptions = usage.subscriptions.saturating_sub(open_subscriptions);
+        }
+    }
+
+    pub fn try_add_ws_subscription(&self, user_id: &str) -> bool {
+        let max_subscriptions = self.settings.websocket.max_subscriptions_per_user;
+        let mut usage = self.user_ws_state.entry(user_id.to_string()).or_default();
+        if usage.subscriptions >= max_subscriptions {
+            return false;
+        }
+        usage.subscriptions += 1;
+        true
+    }
+
+    pub fn remove_ws_subscription(&self, user_id: &str) {
+        if let Some(mut usage) = self.user_ws_state.get_mut(user_id) {
+            usage.subscriptions = usage.subscriptions.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_app_state(extra: &str) -> AppState {
+        AppState::new(&Settings::for_test(extra))
+    }
+
+    #[test]
+    fn test_shed_request_costs_no_credits() {
+        let app_state = test_app_state("[limits]\nglobal_qps = 1");
+        let auth_token: AuthToken =
+            serde_json::from_str(r#"{"user":"alice","exp":0,"qps":10}"#).unwrap();
+        assert!(app_state.check_limits(&auth_token, "getSlot").is_ok());
+        assert!(matches!(
+            app_state.check_limits(&auth_token, "getSlot"),
+            Err(LimitError::Overloaded)
+        ));
+        assert_eq!(app_state.rate_limit_status(&auth_token).remaining, 9);
+    }
+
+    #[test]
+    fn test_quota_rejection_costs_no_credits() {
+        let app_state = test_app_state("[limits]\nglobal_qps = 3");
+        let alice: AuthToken =
+            serde_json::from_str(r#"{"user":"alice","exp":0,"qps":10,"daily_quota":1}"#).unwrap();
+        let bob: AuthToken = serde_json::from_str(r#"{"user":"bob","exp":0,"qps":10}"#).unwrap();
+        assert!(app_state.check_limits(&alice, "getSlot").is_ok());
+        assert!(matches!(
+            app_state.check_limits(&alice, "getSlot"),
+            Err(LimitError::QuotaExceeded(_))
+        ));
+        assert_eq!(app_state.rate_limit_status(&alice).remaining, 9);
+        // The rejected request left its global QPS slot to others
+        assert!(app_state.check_limits(&bob, "getSlot").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_limit() {
+        let app_state = test_app_state(
+            r#"
+            [limits]
+            max_in_flight_per_user = 2
+            queue_timeout_ms = 10
+            "#,
+        );
+        let mut auth_token = AuthToken {
+            user: "alice".to_string(),
+            exp: 0,
+            qps: 1,
+            iat: None,
+            burst: None,
+            daily_quota: None,
+            monthly_quota: None,
+            max_in_flight: None,
+            priority: None,
+            grpc_policy: None,
+            kid: None,
+            alg: None,
+            sig: None,
+        };
+
+        let first = app_state.acquire_in_flight(&auth_token).await.unwrap();
+        let second = app_state.acquire_in_flight(&auth_token).await.unwrap();
+        assert!(app_state.acquire_in_flight(&auth_token).await.is_err());
+        drop(first);
+        assert!(app_state.acquire_in_flight(&auth_token).await.is_ok());
+        drop(second);
+
+        // The token claim overrides the default
+        auth_token.max_in_flight = Some(1);
+        let _only = app_state.acquire_in_flight(&auth_token).await.unwrap();
+        assert!(app_state.acquire_in_flight(&auth_token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_limit_alternating_tokens() {
+        let app_state = test_app_state("[limits]\nqueue_timeout_ms = 10");
+        let token = |max_in_flight| AuthToken {
+            max_in_flight: Some(max_in_flight),
+            ..serde_json::from_str(r#"{"user":"alice","exp":0,"qps":1}"#).unwrap()
+        };
+        let (one, two) = (token(1), token(2));
+
+        // Slots held under one limit count against the other
+        let first = app_state.acquire_in_flight(&two).await.unwrap();
+        let second = app_state.acquire_in_flight(&two).await.unwrap();
+        assert!(app_state.acquire_in_flight(&one).await.is_err());
+        assert!(app_state.acquire_in_flight(&two).await.is_err());
+        drop(first);
+        assert!(app_state.acquire_in_flight(&one).await.is_err());
+        drop(second);
+        let first = app_state.acquire_in_flight(&one).await.unwrap();
+        assert!(app_state.acquire_in_flight(&one).await.is_err());
+
+        let second = app_state.acquire_in_flight(&two).await.unwrap();
+        assert!(app_state.acquire_in_flight(&two).await.is_err());
+        assert!(app_state.acquire_in_flight(&one).await.is_err());
+        drop((first, second));
+        let _first = app_state.acquire_in_flight(&two).await.unwrap();
+        let _second = app_state.acquire_in_flight(&two).await.unwrap();
+        assert!(app_state.acquire_in_flight(&two).await.is_err());
+    }
+
+    #[test]
+    fn test_evict_idle_users() {
+        let app_state = test_app_state("");
+        assert!(app_state.update_and_check_rate_limit("alice", "getSlot", 1000, None));
+        app_state.update_rpc_method_state("alice", "getSlot", 1.0);
+        app_state.update_rpc_method_state("bob", "getSlot", 1.0);
+        assert_eq!(app_state.tracked_users(), 2);
+
+        assert_eq!(app_state.evict_idle_users(Duration::from_secs(60)), 0);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(app_state.evict_idle_users(Duration::from_millis(10)), 2);
+        assert_eq!(app_state.tracked_users(), 0);
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        let mut alice = RpcMethodState::new("getSlot");
+        for response_time in 1..=100 {
+            alice.update(response_time as f64, 1);
+        }
+        let p50 = latency_percentile(&alice.latency, 0.5);
+        let p99 = latency_percentile(&alice.latency, 0.99);
+        assert!((p50 - 50.0).abs() <= 0.5, "p50 = {}", p50);
+        assert!((p99 - 99.0).abs() <= 1.0, "p99 = {}", p99);
+
+        // Histograms of different users merge into one per-method distribution
+        let mut bob = RpcMethodState::new("getSlot");
+        for _ in 0..100 {
+            bob.update(1000.0, 1);
         }
-        user_rpc_method_state.log_if_needed(self.settings.log.user_rpc_log_interval);
+        let mut merged = alice.latency.clone();
+        merged.add(&bob.latency).unwrap();
+        assert_eq!(merged.len(), 200);
+        assert!((latency_percentile(&merged, 0.9) - 1000.0).abs() <= 10.0);
     }
 }
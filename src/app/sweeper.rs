@@ -0,0 +1,22 @@
+use crate::app::state::AppState;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+/// Periodically evicts idle users from the per-user maps in `AppState` and reports
+/// how many users are still tracked.
+pub async fn run_state_sweeper(app_state: Arc<AppState>) {
+    let config = app_state.settings.eviction.clone();
+    let idle_ttl = Duration::from_secs(config.idle_ttl_secs);
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.sweep_interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        let evicted = app_state.evict_idle_users(idle_ttl);
+        info!(
+            event = "idle_users_evicted",
+            evicted = evicted,
+            tracked_users = app_state.tracked_users(),
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::config::Settings;
+use axum::extract::Request;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::Context;
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use tracing::field::Empty;
+use tracing::{Instrument, Span, debug_span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Builds the provider exporting spans to `tracing.otlp_endpoint`, or `None` when it is unset.
+pub fn init_tracer_provider(settings: &Settings) -> Option<SdkTracerProvider> {
+    let config = &settings.tracing;
+    if config.otlp_endpoint.is_empty() {
+        return None;
+    }
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&config.otlp_endpoint)
+        .build()
+        .unwrap_or_else(|err| {
+            eprintln!("Error creating OTLP exporter: {}", err);
+            std::process::exit(1);
+        });
+    // Requests arriving with a sampled `traceparent` are always traced
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio)));
+    Some(
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_sampler(sampler)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(settings.app.name.clone())
+                    .build(),
+            )
+            .build(),
+    )
+}
+
+/// Wraps each proxied request in a `request` span, continuing the caller's trace
+/// when it sends a W3C `traceparent` header.
+pub async fn trace_request(request: Request, next: Next) -> Response {
+    let span = debug_span!(
+        "request",
+        otel.kind = "server",
+        http.method = request.method().as_str(),
+        http.status_code = Empty,
+        user = Empty,
+        request_id = Empty,
+    );
+    let _ = span.set_parent(extract_context(request.headers()));
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("http.status_code", response.status().as_u16());
+    response
+}
+
+/// Adds the `traceparent` (and `tracestate`) of `span` to outgoing headers, so the
+/// upstream's own spans join the trace. Does nothing when the span is not exported.
+pub fn inject_context(span: &Span, headers: &mut HeaderMap) {
+    TraceContextPropagator::new().inject_context(&span.context(), &mut HeaderInjector(headers));
+}
+
+fn extract_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{TraceContextExt, TracerProvider};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_traceparent_propagation() {
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let mut incoming = HeaderMap::new();
+            incoming.insert(
+                "traceparent",
+                HeaderValue::from_static("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+            );
+            let request = debug_span!("request");
+            let _ = request.set_parent(extract_context(&incoming));
+            let upstream = request.in_scope(|| debug_span!("upstream"));
+
+            let mut outgoing = HeaderMap::new();
+            inject_context(&upstream, &mut outgoing);
+            let traceparent = outgoing["traceparent"].to_str().unwrap();
+            // Same trace, new parent span id, still sampled
+            assert!(traceparent.starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+            assert!(traceparent.ends_with("-01"));
+            let span_id = upstream.context().span().span_context().span_id();
+            assert!(traceparent.contains(&span_id.to_string()));
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_otlp_export() {
+        // Stand-in collector recording the paths it receives exports on
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let collector = axum::Router::new().fallback(move |request: Request| async move {
+            let _ = tx.send(request.uri().path().to_string());
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, collector).await });
+
+        let settings = Settings::for_test(&format!(
+            r#"
+            [tracing]
+            otlp_endpoint = "{}"
+            "#,
+            endpoint
+        ));
+        let provider = init_tracer_provider(&settings).unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            debug_span!("request").in_scope(|| {});
+        });
+        // Flushing blocks on the exporter thread
+        tokio::task::spawn_blocking(move || provider.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rx.recv().await.unwrap(), "/v1/traces");
+    }
+
+    #[test]
+    fn test_no_traceparent_without_exporter() {
+        let mut outgoing = HeaderMap::new();
+        inject_context(&debug_span!("upstream"), &mut outgoing);
+        assert!(outgoing.is_empty());
+    }
+}
@@ -1,73 +1,228 @@
+use crate::app::headers::RequestId;
+use crate::app::jsonrpc;
 use crate::app::state::AppState;
-use crate::auth::token::{AuthToken, verify_token};
+use crate::auth::token::{AuthToken, TokenError, verify_token};
+use crate::config::TokenSource;
 use axum::extract::FromRef;
 use axum::{
     Json,
     extract::{FromRequestParts, Query},
-    http::{StatusCode, request::Parts},
+    http::{HeaderMap, HeaderName, StatusCode, header::AUTHORIZATION, request::Parts},
     response::{IntoResponse, Response},
 };
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::Value;
+use std::fmt;
 use std::sync::Arc;
+use tracing::debug_span;
 
 #[derive(Debug)]
 pub struct VerifiedToken(pub AuthToken);
 
+const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
 #[derive(Deserialize)]
 struct TokenQuery {
     token: String,
 }
 
+/// Why a request's token was not accepted.
+#[derive(Debug)]
+pub enum AuthError {
+    Missing,
+    Invalid(TokenError),
+    Expired,
+    Revoked,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "token missing"),
+            AuthError::Invalid(_) => write!(f, "invalid api key provided"),
+            AuthError::Expired => write!(f, "token expired"),
+            AuthError::Revoked => write!(f, "token revoked"),
+        }
+    }
+}
+
+impl AuthError {
+    pub fn code(&self) -> i64 {
+        match self {
+            AuthError::Missing => jsonrpc::TOKEN_MISSING,
+            AuthError::Invalid(_) => jsonrpc::TOKEN_INVALID,
+            AuthError::Expired => jsonrpc::TOKEN_EXPIRED,
+            AuthError::Revoked => jsonrpc::TOKEN_REVOKED,
+        }
+    }
+
+    /// Also the label of the `sentrix_auth_failures_total` metric.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AuthError::Missing => "token_missing",
+            AuthError::Invalid(err) => err.as_str(),
+            AuthError::Expired => "token_expired",
+            AuthError::Revoked => "token_revoked",
+        }
+    }
+}
+
+/// A rejected token, answered with HTTP 401 and a JSON-RPC error object.
+#[derive(Debug)]
+pub struct AuthRejection {
+    pub error: AuthError,
+    pub request_id: String,
+}
+
+impl AuthRejection {
+    pub fn error_object(&self, id: Value) -> Value {
+        jsonrpc::with_data(
+            jsonrpc::error_object(id, self.error.code(), &self.error.to_string()),
+            self.error.reason(),
+            &self.request_id,
+        )
+    }
+}
+
+/// Used when the request body is not available, so the error carries a null `id`.
+impl IntoResponse for AuthRejection {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(self.error_object(Value::Null)),
+        )
+            .into_response()
+    }
+}
+
 impl<S> FromRequestParts<S> for VerifiedToken
 where
     S: Send + Sync,
     Arc<AppState>: FromRef<S>,
 {
-    type Rejection = Response;
+    type Rejection = AuthRejection;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        // Extract 'token' from query parameters
-        let query = Query::<TokenQuery>::from_request_parts(parts, state)
-            .await
-            .map_err(|_| {
-                (
-                    StatusCode::UNAUTHORIZED,
-                    Json(json!({"message": "token missing"})),
-                )
-                    .into_response()
-            })?;
-
         let app_state = Arc::from_ref(state);
+        let result = authenticate(parts, &app_state);
+        result.map_err(|error| {
+            app_state.metrics.record_auth_failure(error.reason());
+            let request_id = match parts.extensions.get::<RequestId>() {
+                Some(request_id) => request_id.0.clone(),
+                None => RequestId::from_headers(&parts.headers).0,
+            };
+            AuthRejection { error, request_id }
+        })
+    }
+}
 
-        // Verify the token using the app's secret key
-        match verify_token(&query.token, &app_state.settings.app.secret_key) {
-            Ok(auth_token) => {
-                let user_id = &auth_token.user;
-                let max_qps = &auth_token.qps;
-                let expiration = auth_token.exp;
-                let now = chrono::Utc::now().timestamp() as u64;
-                if expiration < now {
-                    return Err((
-                        StatusCode::UNAUTHORIZED,
-                        Json(json!({"message": "token expired"})),
-                    )
-                        .into_response());
-                }
-                if !app_state.update_and_check_rate_limit(user_id, *max_qps) {
-                    return Err((
-                        StatusCode::TOO_MANY_REQUESTS,
-                        Json(json!({"message": "rate limit exceeded"})),
-                    )
-                        .into_response());
-                }
-                Ok(VerifiedToken(auth_token))
-            }
-            Err(_) => Err((
-                StatusCode::UNAUTHORIZED,
-                Json(json!({"message": "invalid api key provided"})),
-            )
-                .into_response()),
+fn authenticate(parts: &Parts, app_state: &AppState) -> Result<VerifiedToken, AuthError> {
+    let token =
+        find_token(parts, &app_state.settings.auth.token_sources).ok_or(AuthError::Missing)?;
+
+    // Verify the token against the app's secret key or the keyring
+    let auth_token = debug_span!("auth")
+        .in_scope(|| verify_token(&token, &app_state.keyring))
+        .map_err(AuthError::Invalid)?;
+    if auth_token.is_expired() {
+        return Err(AuthError::Expired);
+    }
+    if app_state.revocations.is_revoked(&auth_token) {
+        return Err(AuthError::Revoked);
+    }
+    Ok(VerifiedToken(auth_token))
+}
+
+/// The token from the first of `sources` the request carries a non-empty one in.
+fn find_token(parts: &Parts, sources: &[TokenSource]) -> Option<String> {
+    sources.iter().find_map(|source| {
+        match source {
+            TokenSource::Bearer => bearer_token(&parts.headers).map(str::to_string),
+            TokenSource::ApiKey => parts
+                .headers
+                .get(X_API_KEY)
+                .and_then(|v| v.to_str().ok())
+                .map(|token| token.trim().to_string()),
+            TokenSource::Path => parts
+                .uri
+                .path()
+                .strip_prefix('/')
+                .filter(|token| !token.contains('/'))
+                .map(str::to_string),
+            TokenSource::Query => Query::<TokenQuery>::try_from_uri(&parts.uri)
+                .ok()
+                .map(|query| query.0.token),
         }
+        // An empty value falls through to the next source
+        .filter(|token| !token.is_empty())
+    })
+}
+
+/// The token of an `Authorization: Bearer <token>` header, whatever the scheme's case.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    fn parts(uri: &str, headers: &[(&str, &str)]) -> Parts {
+        let mut request = Request::post(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn test_find_token() {
+        let all = [
+            TokenSource::Bearer,
+            TokenSource::ApiKey,
+            TokenSource::Path,
+            TokenSource::Query,
+        ];
+        let find =
+            |uri, headers, sources: &[TokenSource]| find_token(&parts(uri, headers), sources);
+
+        assert_eq!(find("/?token=q", &[], &all).as_deref(), Some("q"));
+        assert_eq!(find("/p", &[], &all).as_deref(), Some("p"));
+        assert_eq!(find("/", &[("x-api-key", "k")], &all).as_deref(), Some("k"));
+        assert_eq!(
+            find("/", &[("authorization", "bearer b")], &all).as_deref(),
+            Some("b")
+        );
+        assert_eq!(find("/", &[("authorization", "Basic x")], &all), None);
+        assert_eq!(find("/", &[], &all), None);
+
+        // Precedence follows the configured order
+        let request = (
+            "/p?token=q",
+            [("authorization", "Bearer b"), ("x-api-key", "k")],
+        );
+        assert_eq!(find(request.0, &request.1, &all).as_deref(), Some("b"));
+        let reversed: Vec<TokenSource> = all.iter().rev().copied().collect();
+        assert_eq!(find(request.0, &request.1, &reversed).as_deref(), Some("q"));
+
+        // Empty values do not stop the search
+        assert_eq!(
+            find(
+                "/?token=q",
+                &[("authorization", "Bearer "), ("x-api-key", " ")],
+                &all
+            )
+            .as_deref(),
+            Some("q")
+        );
+
+        // Query-string tokens can be turned off
+        assert_eq!(find("/?token=q", &[], &all[..3]), None);
     }
 }
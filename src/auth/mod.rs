@@ -1,2 +1,3 @@
 pub(crate) mod extractor;
-mod token;
+pub(crate) mod revocation;
+pub(crate) mod token;
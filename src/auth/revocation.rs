@@ -0,0 +1,260 @@
+use crate::app::state::AppState;
+use crate::auth::token::AuthToken;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Tokens refused before their `exp`, as stored in `revocation.file`.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(default)]
+pub struct RevocationList {
+    pub users: BTreeSet<String>,              // Every token of these users
+    pub token_hashes: BTreeSet<String>,       // Hex SHA-256 of a token's `sig`, see `token_hash`
+    pub issued_before: BTreeMap<String, u64>, // user -> tokens with an earlier `iat` (or none)
+}
+
+impl RevocationList {
+    pub fn is_revoked(&self, auth_token: &AuthToken) -> bool {
+        if self.users.contains(&auth_token.user) {
+            return true;
+        }
+        if let Some(&before) = self.issued_before.get(&auth_token.user)
+            && auth_token.iat.unwrap_or(0) < before
+        {
+            return true;
+        }
+        !self.token_hashes.is_empty()
+            && auth_token
+                .sig
+                .as_deref()
+                .is_some_and(|sig| self.token_hashes.contains(&token_hash(sig)))
+    }
+
+    /// Adds the entries of `other`, a later `issued_before` replacing an earlier one.
+    pub fn merge(&mut self, other: RevocationList) {
+        self.users.extend(other.users);
+        self.token_hashes.extend(other.token_hashes);
+        for (user, before) in other.issued_before {
+            let entry = self.issued_before.entry(user).or_default();
+            *entry = (*entry).max(before);
+        }
+    }
+
+    /// Removes the entries of `other`; only the users of its `issued_before` matter.
+    pub fn remove(&mut self, other: &RevocationList) {
+        self.users.retain(|user| !other.users.contains(user));
+        self.token_hashes
+            .retain(|hash| !other.token_hashes.contains(hash));
+        self.issued_before
+            .retain(|user, _| !other.issued_before.contains_key(user));
+    }
+}
+
+/// Identifies a token by its signature, which no other set of claims shares, so
+/// re-encoding a leaked token does not escape its revocation.
+pub fn token_hash(sig: &str) -> String {
+    Sha256::digest(sig.as_bytes())
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+/// The revocation list in effect, kept in sync with `revocation.file` in both directions:
+/// edits to the file are picked up by `run_revocation_watcher`, and changes made
+/// through the admin API are written back to it.
+pub struct Revocations {
+    path: String,
+    list: RwLock<RevocationList>,
+    modified: Mutex<Option<SystemTime>>, // Of the file when last read or written
+    changed: watch::Sender<()>,          // Notified whenever the list is replaced
+}
+
+impl Revocations {
+    /// Loads the list at `path`; a missing file is an empty list, and an empty path
+    /// keeps the list in memory only.
+    pub fn open(path: &str) -> io::Result<Self> {
+        let revocations = Revocations {
+            path: path.to_string(),
+            list: RwLock::new(RevocationList::default()),
+            modified: Mutex::new(None),
+            changed: watch::channel(()).0,
+        };
+        revocations.reload()?;
+        Ok(revocations)
+    }
+
+    pub fn is_revoked(&self, auth_token: &AuthToken) -> bool {
+        self.list.read().unwrap().is_revoked(auth_token)
+    }
+
+    pub fn list(&self) -> RevocationList {
+        self.list.read().unwrap().clone()
+    }
+
+    /// Changes after each update or reload, so long-lived connections can re-check
+    /// their token.
+    pub fn watch(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    /// Applies `change` and persists the result; the list is left untouched when it cannot be saved.
+    pub fn update(&self, change: impl FnOnce(&mut RevocationList)) -> io::Result<RevocationList> {
+        // Held throughout so concurrent updates and reloads do not interleave with the write
+        let mut modified = self.modified.lock().unwrap();
+        let mut list = self.list();
+        change(&mut list);
+        if !self.path.is_empty() {
+            let tmp_path = format!("{}.tmp", self.path);
+            fs::write(&tmp_path, serde_json::to_vec_pretty(&list)?)?;
+            fs::rename(&tmp_path, &self.path)?;
+            *modified = fs::metadata(&self.path)?.modified().ok();
+        }
+        *self.list.write().unwrap() = list.clone();
+        self.changed.send_replace(());
+        Ok(list)
+    }
+
+    /// Re-reads the file if its modification time changed; returns whether it did.
+    pub fn reload(&self) -> io::Result<bool> {
+        if self.path.is_empty() {
+            return Ok(false);
+        }
+        let mut modified = self.modified.lock().unwrap();
+        let current = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        if current == *modified {
+            return Ok(false);
+        }
+        // A broken file is reported once, not on every poll until it is fixed
+        *modified = current;
+        let list = match current {
+            Some(_) => serde_json::from_slice(&fs::read(&self.path)?)?,
+            None => RevocationList::default(),
+        };
+        *self.list.write().unwrap() = list;
+        self.changed.send_replace(());
+        Ok(true)
+    }
+}
+
+/// Polls `revocation.file` every `reload_interval_secs`, swapping in its contents when it
+/// changes. A file that fails to parse is reported and the previous list stays in effect.
+pub async fn run_revocation_watcher(app_state: Arc<AppState>) {
+    let config = app_state.settings.revocation.clone();
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.reload_interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        match app_state.revocations.reload() {
+            Ok(true) => {
+                let list = app_state.revocations.list();
+                info!(
+                    event = "revocations_reloaded",
+                    users = list.users.len(),
+                    token_hashes = list.token_hashes.len(),
+                    issued_before = list.issued_before.len(),
+                );
+            }
+            Ok(false) => {}
+            Err(err) => warn!(event = "revocations_reload_failed", error = err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(user: &str, iat: Option<u64>, sig: &str) -> AuthToken {
+        let mut token: AuthToken =
+            serde_json::from_str(&format!(r#"{{"user":"{}","exp":0,"qps":1}}"#, user)).unwrap();
+        token.iat = iat;
+        token.sig = Some(sig.to_string());
+        token
+    }
+
+    #[test]
+    fn test_is_revoked() {
+        let mut list = RevocationList::default();
+        list.merge(RevocationList {
+            users: BTreeSet::from(["mallory".to_string()]),
+            token_hashes: BTreeSet::from([token_hash("leaked")]),
+            issued_before: BTreeMap::from([("alice".to_string(), 1000)]),
+        });
+
+        assert!(list.is_revoked(&token("mallory", Some(2000), "sig")));
+        assert!(list.is_revoked(&token("bob", None, "leaked")));
+        assert!(!list.is_revoked(&token("bob", None, "sig")));
+        assert!(list.is_revoked(&token("alice", Some(999), "sig")));
+        assert!(list.is_revoked(&token("alice", None, "sig")));
+        assert!(!list.is_revoked(&token("alice", Some(1000), "sig")));
+
+        list.remove(&RevocationList {
+            users: BTreeSet::from(["mallory".to_string()]),
+            issued_before: BTreeMap::from([("alice".to_string(), 0)]),
+            ..Default::default()
+        });
+        assert!(!list.is_revoked(&token("mallory", Some(2000), "sig")));
+        assert!(!list.is_revoked(&token("alice", None, "sig")));
+        assert!(list.is_revoked(&token("bob", None, "leaked")));
+    }
+
+    #[test]
+    fn test_reload_and_persist() {
+        let dir = std::env::temp_dir().join(format!("sentrix-revocations-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("revocations.json");
+        let path_str = path.to_str().unwrap();
+        let _ = fs::remove_file(&path);
+
+        let revocations = Revocations::open(path_str).unwrap();
+        assert_eq!(revocations.list(), RevocationList::default());
+
+        // Admin changes are written to the file...
+        revocations
+            .update(|list| {
+                list.users.insert("mallory".to_string());
+            })
+            .unwrap();
+        let saved: RevocationList = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert!(saved.users.contains("mallory"));
+        assert!(!revocations.reload().unwrap());
+
+        // ...and edits to the file are picked up, unless they do not parse
+        fs::write(&path, r#"{"users": ["eve"]}"#).unwrap();
+        let mtime = SystemTime::now() + Duration::from_secs(1);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        assert!(revocations.reload().unwrap());
+        assert!(revocations.is_revoked(&token("eve", None, "sig")));
+        assert!(!revocations.is_revoked(&token("mallory", None, "sig")));
+
+        fs::write(&path, "not json").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime + Duration::from_secs(1))
+            .unwrap();
+        assert!(revocations.reload().is_err());
+        assert!(revocations.is_revoked(&token("eve", None, "sig")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
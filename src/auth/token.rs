@@ -1,5 +1,7 @@
+use crate::config::{Auth, Priority};
 use base64::{Engine, engine::general_purpose};
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
+use ed25519_dalek::{Signature, VerifyingKey};
 use hmac::{Hmac, KeyInit, Mac};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
@@ -10,11 +12,57 @@ pub struct AuthToken {
     pub exp: u64,     // Expiration time in seconds
     pub qps: u32,     // Queries per second
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub iat: Option<u64>, // Issue time in seconds, checked against `issued_before` revocations
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub burst: Option<u32>, // Requests allowed ahead of the qps schedule, defaults to qps
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub daily_quota: Option<u64>, // Credits per UTC day
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub monthly_quota: Option<u64>, // Credits per UTC month
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub max_in_flight: Option<u32>, // Concurrent HTTP requests allowed
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub priority: Option<Priority>, // Shedding tier when gateway or upstream ceilings are hit
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub grpc_policy: Option<String>, // Named [grpc.policies] entry limiting Subscribe requests
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub kid: Option<String>, // [[auth.keys]] entry the token is signed with; `app.secret_key` when absent
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub alg: Option<Algorithm>, // Signature scheme, `hs256` when absent
+
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(default)]
     pub sig: Option<String>,
 }
 
+/// How a token is signed. Ed25519 tokens are issued with a private key Sentrix never
+/// holds, and must name the `[[auth.keys]]` entry with the matching public key.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    #[default]
+    Hs256,
+    Ed25519,
+}
+
 #[derive(Debug)]
 pub enum TokenError {
     SerializationError,
@@ -22,6 +70,26 @@ pub enum TokenError {
     DecodeError,
     InvalidSignature,
     MissingSignature,
+    UnknownKey,
+    KeyRetired,
+    AlgorithmMismatch,
+    EmptySecret,
+}
+
+impl TokenError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenError::SerializationError => "serialization_error",
+            TokenError::InvalidSecret => "invalid_secret",
+            TokenError::DecodeError => "decode_error",
+            TokenError::InvalidSignature => "invalid_signature",
+            TokenError::MissingSignature => "missing_signature",
+            TokenError::UnknownKey => "unknown_key",
+            TokenError::KeyRetired => "key_retired",
+            TokenError::AlgorithmMismatch => "algorithm_mismatch",
+            TokenError::EmptySecret => "empty_secret",
+        }
+    }
 }
 
 impl AuthToken {
@@ -31,11 +99,39 @@ impl AuthToken {
             user: &'a str,
             exp: u64,
             qps: u32,
+            // Optional claims are only signed when present, so older tokens still verify
+            #[serde(skip_serializing_if = "Option::is_none")]
+            iat: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            burst: Option<u32>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            daily_quota: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            monthly_quota: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            max_in_flight: Option<u32>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            priority: Option<Priority>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            grpc_policy: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            kid: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            alg: Option<Algorithm>,
         }
         let s = SignableToken {
             user: &self.user,
             exp: self.exp,
             qps: self.qps,
+            iat: self.iat,
+            burst: self.burst,
+            daily_quota: self.daily_quota,
+            monthly_quota: self.monthly_quota,
+            max_in_flight: self.max_in_flight,
+            priority: self.priority,
+            grpc_policy: self.grpc_policy.as_deref(),
+            kid: self.kid.as_deref(),
+            alg: self.alg,
         };
         serde_json::to_string(&s).map_err(|_| TokenError::SerializationError)
     }
@@ -52,6 +148,26 @@ impl AuthToken {
         }
         Ok(sig_str)
     }
+
+    fn verify_ed25519_signature(
+        &self,
+        sig: &str,
+        verifying_key: &VerifyingKey,
+    ) -> Result<(), TokenError> {
+        let sig = general_purpose::URL_SAFE_NO_PAD
+            .decode(sig)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or(TokenError::InvalidSignature)?;
+        verifying_key
+            .verify_strict(self.signable_string()?.as_bytes(), &sig)
+            .map_err(|_| TokenError::InvalidSignature)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.exp < Utc::now().timestamp() as u64
+    }
+
     #[allow(dead_code)]
     pub fn generate_token(&self) -> Result<String, TokenError> {
         let token_str = serde_json::to_string(self).map_err(|_| TokenError::SerializationError)?;
@@ -61,11 +177,21 @@ impl AuthToken {
 }
 #[allow(dead_code)]
 pub fn generate_token(secret: &str, user: &str, qps: u32, ttl_secs: u64) -> String {
-    let expiration = (Utc::now() + Duration::seconds(ttl_secs as i64)).timestamp() as u64;
+    let now = Utc::now();
+    let expiration = (now + Duration::seconds(ttl_secs as i64)).timestamp() as u64;
     let mut raw_token = AuthToken {
         user: user.to_string(),
         exp: expiration,
         qps,
+        iat: Some(now.timestamp() as u64),
+        burst: None,
+        daily_quota: None,
+        monthly_quota: None,
+        max_in_flight: None,
+        priority: None,
+        grpc_policy: None,
+        kid: None,
+        alg: None,
         sig: None,
     };
     #[cfg(debug_assertions)]
@@ -95,22 +221,124 @@ pub fn generate_token(secret: &str, user: &str, qps: u32, ttl_secs: u64) -> Stri
     encoded_token
 }
 
-pub fn verify_token(token: &str, secret: &str) -> Result<AuthToken, TokenError> {
+/// The keys tokens are verified with: `app.secret_key` for tokens without a `kid`, and
+/// the `[[auth.keys]]` entries, their public keys decoded once at startup.
+#[derive(Clone)]
+pub struct Keyring {
+    default_secret: String,
+    default_not_after: Option<DateTime<Utc>>,
+    keys: Vec<Key>,
+}
+
+#[derive(Clone)]
+struct Key {
+    id: String,
+    secret: String,
+    public_key: Option<VerifyingKey>,
+    not_after: Option<DateTime<Utc>>,
+}
+
+impl Keyring {
+    /// Fails on a `public_key` that is not a base64 Ed25519 public key.
+    pub fn new(default_secret: &str, auth: &Auth) -> Result<Self, String> {
+        let keys = auth
+            .keys
+            .iter()
+            .map(|key| {
+                let public_key = match key.public_key.as_str() {
+                    "" => None,
+                    encoded => Some(
+                        decode_public_key(encoded)
+                            .ok_or_else(|| format!("invalid public_key for key {}", key.id))?,
+                    ),
+                };
+                Ok(Key {
+                    id: key.id.clone(),
+                    secret: key.secret.clone(),
+                    public_key,
+                    not_after: key.not_after,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Keyring {
+            default_secret: default_secret.to_string(),
+            default_not_after: auth.default_secret_not_after,
+            keys,
+        })
+    }
+
+    /// The entry named `kid`, unless it has been retired.
+    fn find(&self, kid: &str) -> Result<&Key, TokenError> {
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == kid)
+            .ok_or(TokenError::UnknownKey)?;
+        if is_retired(key.not_after) {
+            return Err(TokenError::KeyRetired);
+        }
+        Ok(key)
+    }
+}
+
+fn decode_public_key(encoded: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = general_purpose::STANDARD
+        .decode(encoded)
+        .ok()?
+        .try_into()
+        .ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+fn is_retired(not_after: Option<DateTime<Utc>>) -> bool {
+    not_after.is_some_and(|not_after| Utc::now() > not_after)
+}
+
+/// Reads the claims of `token` without checking its signature.
+pub fn decode_token(token: &str) -> Result<AuthToken, TokenError> {
     let decoded_bytes = general_purpose::URL_SAFE_NO_PAD
         .decode(token)
         .map_err(|_| TokenError::DecodeError)?;
-    let mut auth_token: AuthToken =
-        serde_json::from_slice(&decoded_bytes).map_err(|_| TokenError::DecodeError)?;
+    serde_json::from_slice(&decoded_bytes).map_err(|_| TokenError::DecodeError)
+}
+
+/// Checks the signature of `token` with the key its `kid` names, or with the default
+/// secret, unless retired, when it has none.
+pub fn verify_token(token: &str, keyring: &Keyring) -> Result<AuthToken, TokenError> {
+    let mut auth_token = decode_token(token)?;
 
     let sig = auth_token
         .sig
         .as_ref()
         .ok_or(TokenError::MissingSignature)?
         .to_string();
-    let expected_sig = auth_token.compute_signature(secret, false)?;
-
-    if sig != expected_sig {
-        return Err(TokenError::InvalidSignature);
+    let kid = auth_token.kid.as_deref();
+    match auth_token.alg.unwrap_or_default() {
+        Algorithm::Hs256 => {
+            let secret = match kid {
+                Some(kid) => &keyring.find(kid)?.secret,
+                None if is_retired(keyring.default_not_after) => {
+                    return Err(TokenError::KeyRetired);
+                }
+                None => &keyring.default_secret,
+            };
+            // An empty secret would let anyone sign, e.g. on gateways holding only public keys
+            if secret.is_empty() {
+                return Err(TokenError::EmptySecret);
+            }
+            let expected_sig = auth_token.compute_signature(secret, false)?;
+            if sig != expected_sig {
+                return Err(TokenError::InvalidSignature);
+            }
+        }
+        Algorithm::Ed25519 => {
+            let key = keyring.find(kid.ok_or(TokenError::UnknownKey)?)?;
+            let public_key = key
+                .public_key
+                .as_ref()
+                .ok_or(TokenError::AlgorithmMismatch)?;
+            auth_token.verify_ed25519_signature(&sig, public_key)?;
+        }
     }
     Ok(auth_token)
 }
@@ -118,6 +346,22 @@ pub fn verify_token(token: &str, secret: &str) -> Result<AuthToken, TokenError>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::SigningKey;
+    use ed25519_dalek::{Signer, SigningKey as Ed25519SigningKey};
+
+    fn keyring(default_secret: &str, keys: &[SigningKey]) -> Keyring {
+        let auth = Auth {
+            keys: keys.to_vec(),
+            ..Default::default()
+        };
+        Keyring::new(default_secret, &auth).unwrap()
+    }
+
+    /// Signs as an Ed25519 issuer would; Sentrix itself only holds public keys.
+    fn sign_ed25519(token: &mut AuthToken, signing_key: &Ed25519SigningKey) {
+        let sig = signing_key.sign(token.signable_string().unwrap().as_bytes());
+        token.sig = Some(general_purpose::URL_SAFE_NO_PAD.encode(sig.to_bytes()));
+    }
 
     #[test]
     fn test_generate_token() {
@@ -138,7 +382,163 @@ mod tests {
 
         println!("Generated token: {}", token);
 
-        let verified_token = verify_token(&token, &secret).unwrap();
+        let verified_token =
+            verify_token(&token, &Keyring::new(&secret, &settings.auth).unwrap()).unwrap();
         println!("Verified token: {:?}", verified_token);
     }
+
+    #[test]
+    fn test_verify_with_keyring() {
+        use chrono::TimeZone;
+        let keys = [
+            SigningKey {
+                id: "2025".to_string(),
+                secret: "old-secret".to_string(),
+                public_key: String::new(),
+                not_after: Some(Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()),
+            },
+            SigningKey {
+                id: "2026".to_string(),
+                secret: "new-secret".to_string(),
+                public_key: String::new(),
+                not_after: None,
+            },
+        ];
+        let keyring = keyring("default-secret", &keys);
+        let sign = |kid: Option<&str>, secret: &str| {
+            let mut token: AuthToken =
+                serde_json::from_str(r#"{"user":"alice","exp":4102444800,"qps":10}"#).unwrap();
+            token.kid = kid.map(str::to_string);
+            token.compute_signature(secret, true).unwrap();
+            token.generate_token().unwrap()
+        };
+
+        let token = sign(Some("2026"), "new-secret");
+        let verified = verify_token(&token, &keyring).unwrap();
+        assert_eq!(verified.kid.as_deref(), Some("2026"));
+        // Tokens without a `kid` keep using the default secret
+        assert!(verify_token(&sign(None, "default-secret"), &keyring).is_ok());
+
+        let token = sign(Some("2026"), "old-secret");
+        assert!(matches!(
+            verify_token(&token, &keyring),
+            Err(TokenError::InvalidSignature)
+        ));
+        let token = sign(Some("2024"), "old-secret");
+        assert!(matches!(
+            verify_token(&token, &keyring),
+            Err(TokenError::UnknownKey)
+        ));
+        let token = sign(Some("2025"), "old-secret");
+        assert!(matches!(
+            verify_token(&token, &keyring),
+            Err(TokenError::KeyRetired)
+        ));
+
+        // The default secret can be retired too
+        let auth = Auth {
+            keys: keys.to_vec(),
+            default_secret_not_after: Some(Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap()),
+            ..Default::default()
+        };
+        let retired = Keyring::new("default-secret", &auth).unwrap();
+        let token = sign(None, "default-secret");
+        assert!(matches!(
+            verify_token(&token, &retired),
+            Err(TokenError::KeyRetired)
+        ));
+        let token = sign(Some("2026"), "new-secret");
+        assert!(verify_token(&token, &retired).is_ok());
+    }
+
+    #[test]
+    fn test_verify_ed25519() {
+        let signing_key = Ed25519SigningKey::from_bytes(&[7; 32]);
+        let keys = [
+            SigningKey {
+                id: "issuer".to_string(),
+                secret: String::new(),
+                public_key: general_purpose::STANDARD
+                    .encode(signing_key.verifying_key().to_bytes()),
+                not_after: None,
+            },
+            SigningKey {
+                id: "hmac".to_string(),
+                secret: "hmac-secret".to_string(),
+                public_key: String::new(),
+                not_after: None,
+            },
+        ];
+        let keyring = keyring("", &keys);
+        let sign = |kid: &str, signing_key: &Ed25519SigningKey| {
+            let mut token: AuthToken =
+                serde_json::from_str(r#"{"user":"alice","exp":4102444800,"qps":10}"#).unwrap();
+            token.kid = Some(kid.to_string());
+            token.alg = Some(Algorithm::Ed25519);
+            sign_ed25519(&mut token, signing_key);
+            token
+        };
+
+        let token = sign("issuer", &signing_key).generate_token().unwrap();
+        let verified = verify_token(&token, &keyring).unwrap();
+        assert_eq!(verified.alg, Some(Algorithm::Ed25519));
+
+        let forged = sign("issuer", &Ed25519SigningKey::from_bytes(&[8; 32]));
+        assert!(matches!(
+            verify_token(&forged.generate_token().unwrap(), &keyring),
+            Err(TokenError::InvalidSignature)
+        ));
+        // Claims are covered by the signature
+        let mut tampered = sign("issuer", &signing_key);
+        tampered.qps = 1000;
+        assert!(matches!(
+            verify_token(&tampered.generate_token().unwrap(), &keyring),
+            Err(TokenError::InvalidSignature)
+        ));
+        // Neither algorithm may use the other's key, nor an empty HMAC secret
+        let token = sign("hmac", &signing_key).generate_token().unwrap();
+        assert!(matches!(
+            verify_token(&token, &keyring),
+            Err(TokenError::AlgorithmMismatch)
+        ));
+        let mut downgraded = sign("issuer", &signing_key);
+        downgraded.alg = None;
+        downgraded.compute_signature("", true).unwrap();
+        assert!(matches!(
+            verify_token(&downgraded.generate_token().unwrap(), &keyring),
+            Err(TokenError::EmptySecret)
+        ));
+        let mut unsigned = sign("issuer", &signing_key);
+        unsigned.kid = None;
+        unsigned.alg = None;
+        unsigned.compute_signature("", true).unwrap();
+        assert!(matches!(
+            verify_token(&unsigned.generate_token().unwrap(), &keyring),
+            Err(TokenError::EmptySecret)
+        ));
+    }
+
+    #[test]
+    fn test_keyring_rejects_bad_public_key() {
+        let key = |public_key: &str| SigningKey {
+            id: "issuer".to_string(),
+            secret: String::new(),
+            public_key: public_key.to_string(),
+            not_after: None,
+        };
+        let auth = |key| Auth {
+            keys: vec![key],
+            ..Default::default()
+        };
+        assert!(Keyring::new("", &auth(key("not base64!"))).is_err());
+        assert!(Keyring::new("", &auth(key(&general_purpose::STANDARD.encode([1; 16])))).is_err());
+        let public_key = Ed25519SigningKey::from_bytes(&[7; 32]).verifying_key();
+        assert!(
+            Keyring::new(
+                "",
+                &auth(key(&general_purpose::STANDARD.encode(public_key)))
+            )
+            .is_ok()
+        );
+    }
 }
@@ -1,11 +1,33 @@
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Deserialize, Clone)]
 pub struct Settings {
     pub app: App,
+    #[serde(default)]
+    pub auth: Auth,
+    #[serde(default)]
+    pub revocation: Revocation,
     pub backend: Backend,
     pub http_client: HttpClient,
     pub log: Log,
+    #[serde(default)]
+    pub websocket: Websocket,
+    #[serde(default)]
+    pub grpc: Grpc,
+    #[serde(default)]
+    pub credits: Credits,
+    #[serde(default)]
+    pub quota: Quota,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub eviction: Eviction,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub tracing: Tracing,
 }
 
 #[derive(Deserialize, Clone)]
@@ -13,17 +35,193 @@ pub struct App {
     pub name: String,
     pub port: u16,
     pub secret_key: String,
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize, // Maximum number of requests in a JSON-RPC batch
+}
+
+fn default_max_batch_size() -> usize {
+    100
+}
+
+/// Where HTTP and WebSocket requests may carry their token, and the keys it may be signed with.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Auth {
+    pub token_sources: Vec<TokenSource>, // In order of precedence; unlisted sources are ignored
+    pub keys: Vec<SigningKey>, // Keyring for tokens with a `kid` claim; `app.secret_key` verifies the rest
+    pub default_secret_not_after: Option<DateTime<Utc>>, // RFC 3339; tokens without a `kid` are rejected afterwards
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth {
+            token_sources: vec![
+                TokenSource::Bearer,
+                TokenSource::ApiKey,
+                TokenSource::Path,
+                TokenSource::Query,
+            ],
+            keys: Vec::new(),
+            default_secret_not_after: None,
+        }
+    }
+}
+
+/// A named token signing key: an HMAC secret, or the public half of an Ed25519 key pair.
+/// Keep a rotated-out key listed with a `not_after` until the tokens it signed have expired.
+#[derive(Deserialize, Clone)]
+pub struct SigningKey {
+    pub id: String, // Matched against the token's `kid` claim
+    #[serde(default)]
+    pub secret: String, // HMAC secret, for `hs256` tokens
+    #[serde(default)]
+    pub public_key: String, // Base64 Ed25519 public key, for `ed25519` tokens
+    #[serde(default)]
+    pub not_after: Option<DateTime<Utc>>, // RFC 3339; tokens signed with the key are rejected afterwards
+}
+
+/// Tokens refused before they expire, see `auth::revocation`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Revocation {
+    pub file: String, // JSON revocation list, reloaded when modified; empty keeps the list in memory only
+    pub reload_interval_secs: u64, // How often the file's modification time is checked
+    pub admin_token: String, // Bearer token for the /admin/revocations API; the API is disabled when empty
+}
+
+impl Default for Revocation {
+    fn default() -> Self {
+        Revocation {
+            file: String::new(),
+            reload_interval_secs: 5,
+            admin_token: String::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenSource {
+    Bearer, // Authorization: Bearer <token>
+    ApiKey, // X-Api-Key: <token>
+    Path,   // POST /<token>
+    Query,  // POST /?token=<token>
 }
 
 #[derive(Deserialize, Clone)]
 pub struct Backend {
-    pub rpc_url: String,
-    #[allow(dead_code)]
-    pub yellowstone_grpc_url: String,
-    #[allow(dead_code)]
+    #[serde(default)]
+    pub rpc_url: String, // Single upstream, used when no [[backend.rpc]] entries are given
+    #[serde(default)]
+    pub rpc: Vec<RpcUpstream>,
+    #[serde(default)]
+    pub strategy: LoadBalanceStrategy,
+    #[serde(default)]
+    pub pools: Vec<PoolConfig>, // Named pools in addition to the default one above
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    #[serde(default)]
+    pub health_check: HealthCheck,
+    #[serde(default)]
+    pub retry: Retry,
+    #[serde(default)]
+    pub ws_url: String, // Upstream PubSub endpoint; websocket proxying is disabled when empty
+    pub yellowstone_grpc_url: String, // gRPC proxying is disabled when empty
     pub yellowstone_grpc_token: String,
 }
 
+#[derive(Deserialize, Clone)]
+pub struct RpcUpstream {
+    pub url: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    pub max_qps: Option<u32>, // Requests per second this upstream may receive
+    pub max_in_flight: Option<usize>, // Concurrent requests this upstream may receive
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalanceStrategy {
+    #[default]
+    RoundRobin,
+    Weighted,
+    LeastInFlight,
+    LatencyEwma,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PoolConfig {
+    pub name: String,
+    #[serde(default)]
+    pub strategy: LoadBalanceStrategy,
+    pub rpc: Vec<RpcUpstream>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Route {
+    pub methods: Vec<String>, // Exact method names or glob patterns, e.g. "getSignatures*"
+    pub pool: String,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct HealthCheck {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    pub max_slot_lag: u64, // Upstreams further behind the highest observed slot are ejected
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            enabled: true,
+            interval_secs: 5,
+            timeout_secs: 2,
+            max_slot_lag: 50,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Retry {
+    pub max_retries: u32,
+    pub backoff_ms: u64, // Delay before the first retry, doubled on each further attempt
+    pub max_backoff_ms: u64,
+    pub retry_write_methods: bool, // Also retry sendTransaction-class methods
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            max_retries: 2,
+            backoff_ms: 50,
+            max_backoff_ms: 1000,
+            retry_write_methods: false,
+        }
+    }
+}
+
+impl Backend {
+    /// Returns the configured RPC upstreams, falling back to the legacy `rpc_url`.
+    pub fn rpc_upstreams(&self) -> Vec<RpcUpstream> {
+        if self.rpc.is_empty() && !self.rpc_url.is_empty() {
+            return vec![RpcUpstream {
+                url: self.rpc_url.clone(),
+                weight: default_weight(),
+                max_qps: None,
+                max_in_flight: None,
+            }];
+        }
+        self.rpc.clone()
+    }
+}
+
 #[derive(Deserialize, Clone)]
 pub struct HttpClient {
     pub pool_max_idle_per_host: usize,
@@ -31,6 +229,69 @@ pub struct HttpClient {
     pub connect_timeout_secs: u64,
     pub pool_idle_timeout_secs: u64,
 }
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Websocket {
+    pub max_connections_per_user: usize,
+    pub max_subscriptions_per_user: usize, // Across all of a user's connections
+}
+
+impl Default for Websocket {
+    fn default() -> Self {
+        Websocket {
+            max_connections_per_user: 10,
+            max_subscriptions_per_user: 100,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Grpc {
+    pub port: u16,
+    pub max_message_size: usize, // In bytes, applies to both directions
+    pub default_policy: Option<String>, // Applied to tokens without a `grpc_policy` claim
+    pub policies: HashMap<String, SubscribePolicy>,
+}
+
+impl Default for Grpc {
+    fn default() -> Self {
+        Grpc {
+            port: 10000,
+            max_message_size: 64 * 1024 * 1024,
+            default_policy: None,
+            policies: HashMap::new(),
+        }
+    }
+}
+
+/// Limits on what a Yellowstone Subscribe request may ask for. Everything is allowed by default.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SubscribePolicy {
+    pub max_account_filters: Option<usize>, // Total accounts listed across account filters
+    pub max_owner_filters: Option<usize>,   // Total owners listed across account filters
+    pub allow_account_firehose: bool,       // Account filters with neither accounts nor owners
+    pub allow_transaction_firehose: bool,   // Transaction filters with no signature or accounts
+    pub allowed_commitments: Vec<String>,   // Empty allows all of processed, confirmed, finalized
+    pub allow_blocks: bool,
+    pub allow_entries: bool,
+}
+
+impl Default for SubscribePolicy {
+    fn default() -> Self {
+        SubscribePolicy {
+            max_account_filters: None,
+            max_owner_filters: None,
+            allow_account_firehose: true,
+            allow_transaction_firehose: true,
+            allowed_commitments: Vec::new(),
+            allow_blocks: true,
+            allow_entries: true,
+        }
+    }
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Log {
     pub file: String,
@@ -38,6 +299,154 @@ pub struct Log {
     pub user_rpc_log_interval: u64,
 }
 
+/// Rate-limit cost of each RPC method, so token `qps` is spent in credits per second.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Credits {
+    pub default_cost: u32,             // Cost of methods missing from `methods`
+    pub methods: HashMap<String, u32>, // rpc_method -> cost
+}
+
+impl Default for Credits {
+    fn default() -> Self {
+        Credits {
+            default_cost: 1,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+impl Credits {
+    pub fn cost(&self, rpc_method: &str) -> u32 {
+        self.methods
+            .get(rpc_method)
+            .copied()
+            .unwrap_or(self.default_cost)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Quota {
+    pub db_path: String, // SQLite file persisting quota usage; empty keeps it in memory only
+    pub flush_interval_secs: u64,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Quota {
+            db_path: String::new(),
+            flush_interval_secs: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Limits {
+    pub max_in_flight_per_user: Option<usize>, // Default for tokens without a `max_in_flight` claim
+    pub queue_timeout_ms: u64, // How long a request waits for an in-flight slot before rejection
+    pub global_qps: Option<u32>, // Requests per second across all users
+    pub global_max_in_flight: Option<usize>, // Concurrent HTTP requests across all users
+    pub default_priority: Priority, // For tokens without a `priority` claim
+    pub priority_share: PriorityShare,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_in_flight_per_user: None,
+            queue_timeout_ms: 500,
+            global_qps: None,
+            global_max_in_flight: None,
+            default_priority: Priority::Normal,
+            priority_share: PriorityShare::default(),
+        }
+    }
+}
+
+/// Priority tier of a token. When a ceiling fills up, lower tiers are shed first.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Fraction of each global and per-upstream ceiling a priority tier may use.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct PriorityShare {
+    pub low: f64,
+    pub normal: f64,
+    pub high: f64,
+}
+
+impl Default for PriorityShare {
+    fn default() -> Self {
+        PriorityShare {
+            low: 0.7,
+            normal: 0.9,
+            high: 1.0,
+        }
+    }
+}
+
+impl PriorityShare {
+    pub fn get(&self, priority: Priority) -> f64 {
+        match priority {
+            Priority::Low => self.low,
+            Priority::Normal => self.normal,
+            Priority::High => self.high,
+        }
+    }
+}
+
+/// Background removal of per-user state for users that stopped sending traffic.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Eviction {
+    pub idle_ttl_secs: u64, // Users idle for longer are forgotten, after flushing their stats
+    pub sweep_interval_secs: u64,
+}
+
+impl Default for Eviction {
+    fn default() -> Self {
+        Eviction {
+            idle_ttl_secs: 3600,
+            sweep_interval_secs: 60,
+        }
+    }
+}
+
+/// Prometheus `/metrics` endpoint, disabled by default.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Metrics {
+    pub enabled: bool,
+    pub port: Option<u16>, // Separate listener for /metrics; served on app.port when unset
+    pub per_user: bool,    // Label requests with the user; one series per user and method
+}
+
+/// OpenTelemetry span export over OTLP/HTTP.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Tracing {
+    pub otlp_endpoint: String, // e.g. "http://collector:4318/v1/traces"; export is disabled when empty
+    pub sample_ratio: f64,     // Share of new traces exported; sampled parent traces always are
+}
+
+impl Default for Tracing {
+    fn default() -> Self {
+        Tracing {
+            otlp_endpoint: String::new(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
 impl Settings {
     pub fn new() -> Result<Self, config::ConfigError> {
         config::Config::builder()
@@ -45,4 +454,67 @@ impl Settings {
             .build()?
             .try_deserialize()
     }
+
+    #[cfg(test)]
+    pub fn from_toml(toml: &str) -> Self {
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .unwrap()
+    }
+
+    /// The required sections with values that keep tests offline and in memory,
+    /// followed by the `extra` sections a test needs.
+    #[cfg(test)]
+    pub fn for_test(extra: &str) -> Self {
+        Settings::from_toml(&format!(
+            r#"
+            [app]
+            name = "sentrix"
+            port = 0
+            secret_key = "test-secret"
+
+            [backend]
+            rpc_url = "http://127.0.0.1:1"
+            yellowstone_grpc_url = ""
+            yellowstone_grpc_token = ""
+
+            [http_client]
+            pool_max_idle_per_host = 1
+            timeout_secs = 1
+            connect_timeout_secs = 1
+            pool_idle_timeout_secs = 1
+
+            [log]
+            file = ""
+            level = "info"
+            user_rpc_log_interval = 60
+
+            {}
+            "#,
+            extra
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credits_cost() {
+        let settings = Settings::for_test(
+            r#"
+            [credits]
+            default_cost = 2
+
+            [credits.methods]
+            getProgramAccounts = 100
+            "#,
+        );
+        // Method names are case sensitive
+        assert_eq!(settings.credits.cost("getProgramAccounts"), 100);
+        assert_eq!(settings.credits.cost("getSlot"), 2);
+    }
 }
@@ -0,0 +1,68 @@
+//! Partial protobuf definitions of the Yellowstone `geyser.proto` messages Sentrix inspects.
+//! Only the fields needed for policy checks are declared; everything else is skipped on
+//! decode, and the original bytes are what get forwarded upstream.
+
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequest {
+    #[prost(map = "string, message", tag = "1")]
+    pub accounts: HashMap<String, SubscribeRequestFilterAccounts>,
+    #[prost(map = "string, message", tag = "3")]
+    pub transactions: HashMap<String, SubscribeRequestFilterTransactions>,
+    #[prost(map = "string, message", tag = "10")]
+    pub transactions_status: HashMap<String, SubscribeRequestFilterTransactions>,
+    #[prost(map = "string, message", tag = "4")]
+    pub blocks: HashMap<String, SubscribeRequestFilterBlocks>,
+    #[prost(map = "string, message", tag = "8")]
+    pub entry: HashMap<String, SubscribeRequestFilterEntry>,
+    #[prost(enumeration = "CommitmentLevel", optional, tag = "6")]
+    pub commitment: Option<i32>,
+    #[prost(message, optional, tag = "9")]
+    pub ping: Option<SubscribeRequestPing>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequestFilterAccounts {
+    #[prost(string, repeated, tag = "2")]
+    pub account: Vec<String>,
+    #[prost(string, repeated, tag = "3")]
+    pub owner: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequestFilterTransactions {
+    #[prost(string, optional, tag = "5")]
+    pub signature: Option<String>,
+    #[prost(string, repeated, tag = "3")]
+    pub account_include: Vec<String>,
+    #[prost(string, repeated, tag = "6")]
+    pub account_required: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequestFilterBlocks {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequestPing {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequestFilterEntry {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum CommitmentLevel {
+    Processed = 0,
+    Confirmed = 1,
+    Finalized = 2,
+}
+
+impl CommitmentLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommitmentLevel::Processed => "processed",
+            CommitmentLevel::Confirmed => "confirmed",
+            CommitmentLevel::Finalized => "finalized",
+        }
+    }
+}
@@ -0,0 +1,4 @@
+mod codec;
+mod geyser;
+mod policy;
+pub mod proxy;
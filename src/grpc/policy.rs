@@ -0,0 +1,195 @@
+use crate::config::SubscribePolicy;
+use crate::grpc::geyser::{CommitmentLevel, SubscribeRequest, SubscribeRequestFilterTransactions};
+use bytes::Bytes;
+use prost::Message;
+use tonic::Status;
+
+/// Decodes a raw Subscribe message and checks it. Bare pings leave the filters untouched and
+/// always pass; a ping that also carries filters is checked like any other request.
+pub fn check_message(policy: &SubscribePolicy, message: &Bytes) -> Result<(), Status> {
+    let request = SubscribeRequest::decode(message.as_ref())
+        .map_err(|err| Status::invalid_argument(format!("invalid subscribe request: {}", err)))?;
+    if request.ping.is_some() && has_no_filters(&request) {
+        return Ok(());
+    }
+    check(policy, &request)
+}
+
+fn has_no_filters(request: &SubscribeRequest) -> bool {
+    request.accounts.is_empty()
+        && request.transactions.is_empty()
+        && request.transactions_status.is_empty()
+        && request.blocks.is_empty()
+        && request.entry.is_empty()
+}
+
+/// Checks a Subscribe request against the policy, before it reaches the upstream.
+pub fn check(policy: &SubscribePolicy, request: &SubscribeRequest) -> Result<(), Status> {
+    let accounts: usize = request.accounts.values().map(|f| f.account.len()).sum();
+    if let Some(max) = policy.max_account_filters
+        && accounts > max
+    {
+        return Err(Status::permission_denied(format!(
+            "subscribe requests {} accounts, policy allows at most {}",
+            accounts, max
+        )));
+    }
+    let owners: usize = request.accounts.values().map(|f| f.owner.len()).sum();
+    if let Some(max) = policy.max_owner_filters
+        && owners > max
+    {
+        return Err(Status::permission_denied(format!(
+            "subscribe requests {} owners, policy allows at most {}",
+            owners, max
+        )));
+    }
+
+    if !policy.allow_account_firehose
+        && request
+            .accounts
+            .values()
+            .any(|f| f.account.is_empty() && f.owner.is_empty())
+    {
+        return Err(Status::permission_denied(
+            "account filters without accounts or owners are not allowed",
+        ));
+    }
+    if !policy.allow_transaction_firehose
+        && request
+            .transactions
+            .values()
+            .chain(request.transactions_status.values())
+            .any(is_firehose)
+    {
+        return Err(Status::permission_denied(
+            "transaction filters without a signature or accounts are not allowed",
+        ));
+    }
+    if !policy.allow_blocks && !request.blocks.is_empty() {
+        return Err(Status::permission_denied(
+            "block subscriptions are not allowed",
+        ));
+    }
+    if !policy.allow_entries && !request.entry.is_empty() {
+        return Err(Status::permission_denied(
+            "entry subscriptions are not allowed",
+        ));
+    }
+
+    if !policy.allowed_commitments.is_empty() {
+        // Yellowstone defaults to processed when no commitment is given
+        let commitment = request
+            .commitment
+            .and_then(|c| CommitmentLevel::try_from(c).ok())
+            .unwrap_or(CommitmentLevel::Processed);
+        if !policy
+            .allowed_commitments
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(commitment.as_str()))
+        {
+            return Err(Status::permission_denied(format!(
+                "commitment '{}' is not allowed",
+                commitment.as_str()
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn is_firehose(filter: &SubscribeRequestFilterTransactions) -> bool {
+    filter.signature.is_none()
+        && filter.account_include.is_empty()
+        && filter.account_required.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::geyser::{SubscribeRequestFilterAccounts, SubscribeRequestFilterBlocks};
+
+    fn accounts(account: &[&str], owner: &[&str]) -> SubscribeRequestFilterAccounts {
+        SubscribeRequestFilterAccounts {
+            account: account.iter().map(|a| a.to_string()).collect(),
+            owner: owner.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_default_policy_allows_everything() {
+        let mut request = SubscribeRequest::default();
+        request.accounts.insert("all".into(), accounts(&[], &[]));
+        request
+            .transactions
+            .insert("all".into(), SubscribeRequestFilterTransactions::default());
+        request
+            .blocks
+            .insert("all".into(), SubscribeRequestFilterBlocks::default());
+        assert!(check(&SubscribePolicy::default(), &request).is_ok());
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = SubscribePolicy {
+            max_account_filters: Some(2),
+            allow_account_firehose: false,
+            allow_transaction_firehose: false,
+            allowed_commitments: vec!["confirmed".into(), "finalized".into()],
+            ..SubscribePolicy::default()
+        };
+
+        let mut request = SubscribeRequest {
+            commitment: Some(CommitmentLevel::Confirmed as i32),
+            ..SubscribeRequest::default()
+        };
+        request.accounts.insert("a".into(), accounts(&["A1"], &[]));
+        request
+            .accounts
+            .insert("b".into(), accounts(&["B1"], &["P"]));
+        assert!(check(&policy, &request).is_ok());
+
+        request.accounts.insert("c".into(), accounts(&["C1"], &[]));
+        let status = check(&policy, &request).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        request.accounts.remove("c");
+
+        request.accounts.insert("all".into(), accounts(&[], &[]));
+        assert!(check(&policy, &request).is_err());
+        request.accounts.remove("all");
+
+        request
+            .transactions
+            .insert("all".into(), SubscribeRequestFilterTransactions::default());
+        assert!(check(&policy, &request).is_err());
+        request.transactions.get_mut("all").unwrap().account_include = vec!["A1".into()];
+        assert!(check(&policy, &request).is_ok());
+
+        request.commitment = None; // Processed
+        assert!(check(&policy, &request).is_err());
+    }
+
+    #[test]
+    fn test_check_message() {
+        let policy = SubscribePolicy {
+            allow_blocks: false,
+            ..SubscribePolicy::default()
+        };
+        let mut request = SubscribeRequest::default();
+        request
+            .blocks
+            .insert("all".into(), SubscribeRequestFilterBlocks::default());
+        let message = Bytes::from(request.encode_to_vec());
+        assert!(check_message(&policy, &message).is_err());
+
+        // A ping cannot smuggle in filters the policy forbids
+        request.ping = Some(Default::default());
+        let message = Bytes::from(request.encode_to_vec());
+        assert!(check_message(&policy, &message).is_err());
+
+        request.blocks.clear();
+        let message = Bytes::from(request.encode_to_vec());
+        assert!(check_message(&policy, &message).is_ok());
+
+        let status = check_message(&policy, &Bytes::from_static(b"\xff")).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -0,0 +1,545 @@
+use crate::app::headers::RequestId;
+use crate::app::state::{AppState, LimitError};
+use crate::auth::extractor::AuthError;
+use crate::auth::token::{AuthToken, verify_token};
+use crate::config::SubscribePolicy;
+use crate::grpc::codec::BytesCodec;
+use crate::grpc::policy;
+use bytes::Bytes;
+use futures::StreamExt;
+use futures::stream::{self, BoxStream};
+use std::convert::Infallible;
+use std::future::{pending, ready};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::oneshot;
+use tonic::body::Body;
+use tonic::client::Grpc;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::codegen::http::{HeaderMap, Request, Response};
+use tonic::codegen::{BoxFuture, Context, Poll, Service};
+use tonic::metadata::{AsciiMetadataValue, MetadataMap};
+use tonic::server::{NamedService, StreamingService};
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint, Server};
+use tonic::{Extensions, Status, Streaming};
+use tracing::trace;
+
+// Metadata key carrying the token, as used by Yellowstone clients
+const TOKEN_METADATA_KEY: &str = "x-token";
+// The only method whose requests are checked against a SubscribePolicy
+const SUBSCRIBE_PATH: &str = "/geyser.Geyser/Subscribe";
+
+/// Relays every `geyser.Geyser` method to the Yellowstone upstream. Clients authenticate
+/// with a Sentrix token in `x-token`, which is replaced by the upstream token on the way out.
+#[derive(Clone)]
+pub struct GeyserProxy {
+    app_state: Arc<AppState>,
+    upstream: Grpc<Channel>,
+    upstream_token: Option<AsciiMetadataValue>,
+}
+
+impl NamedService for GeyserProxy {
+    const NAME: &'static str = "geyser.Geyser";
+}
+
+impl GeyserProxy {
+    pub fn new(app_state: Arc<AppState>) -> Result<Self, String> {
+        let backend = &app_state.settings.backend;
+        let mut endpoint = Endpoint::from_shared(backend.yellowstone_grpc_url.clone())
+            .map_err(|err| format!("invalid yellowstone_grpc_url: {}", err))?;
+        if backend.yellowstone_grpc_url.starts_with("https://") {
+            endpoint = endpoint
+                .tls_config(ClientTlsConfig::new().with_native_roots())
+                .map_err(|err| format!("invalid TLS configuration: {}", err))?;
+        }
+        let grpc = &app_state.settings.grpc;
+        if let Some(name) = &grpc.default_policy
+            && !grpc.policies.contains_key(name)
+        {
+            return Err(format!(
+                "default_policy refers to unknown policy '{}'",
+                name
+            ));
+        }
+        let upstream_token = if backend.yellowstone_grpc_token.is_empty() {
+            None
+        } else {
+            Some(
+                backend
+                    .yellowstone_grpc_token
+                    .parse()
+                    .map_err(|_| "invalid yellowstone_grpc_token".to_string())?,
+            )
+        };
+
+        let max_message_size = app_state.settings.grpc.max_message_size;
+        let upstream = Grpc::new(endpoint.connect_lazy())
+            .max_decoding_message_size(max_message_size)
+            .max_encoding_message_size(max_message_size);
+        Ok(GeyserProxy {
+            app_state,
+            upstream,
+            upstream_token,
+        })
+    }
+
+    async fn handle(self, request: Request<Body>) -> Response<Body> {
+        let auth_token = match self.authenticate(request.headers()) {
+            Ok(auth_token) => auth_token,
+            Err(status) => return status.into_http(),
+        };
+        let path = request
+            .uri()
+            .path_and_query()
+            .cloned()
+            .unwrap_or_else(|| PathAndQuery::from_static("/"));
+        self.app_state.quota.warm_up(&auth_token.user).await;
+        match self.app_state.check_limits(&auth_token, grpc_method(&path)) {
+            Ok(()) => {}
+            Err(LimitError::Overloaded) => {
+                return Status::unavailable(LimitError::Overloaded.to_string()).into_http();
+            }
+            Err(err) => return Status::resource_exhausted(err.to_string()).into_http(),
+        }
+        let policy = match self.subscribe_policy(&auth_token) {
+            Ok(policy) => policy,
+            Err(status) => return status.into_http(),
+        };
+
+        let RequestId(request_id) = RequestId::from_headers(request.headers());
+        trace!(
+            event = "grpc_request_received",
+            user = auth_token.user,
+            path = path.as_str(),
+            max_qps = auth_token.qps,
+            exp = auth_token.exp,
+            request_id = request_id
+        );
+
+        let max_message_size = self.app_state.settings.grpc.max_message_size;
+        let relay = Relay {
+            proxy: self,
+            path,
+            auth_token: Arc::new(auth_token),
+            request_id,
+            policy,
+        };
+        let mut server = tonic::server::Grpc::new(BytesCodec)
+            .max_decoding_message_size(max_message_size)
+            .max_encoding_message_size(max_message_size);
+        server.streaming(relay, request).await
+    }
+
+    fn authenticate(&self, headers: &HeaderMap) -> Result<AuthToken, Status> {
+        self.verify(headers).map_err(|err| {
+            self.app_state.metrics.record_auth_failure(err.reason());
+            Status::unauthenticated(err.to_string())
+        })
+    }
+
+    fn verify(&self, headers: &HeaderMap) -> Result<AuthToken, AuthError> {
+        let token = headers
+            .get(TOKEN_METADATA_KEY)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(AuthError::Missing)?;
+        let auth_token =
+            verify_token(token, &self.app_state.keyring).map_err(AuthError::Invalid)?;
+        if auth_token.is_expired() {
+            return Err(AuthError::Expired);
+        }
+        if self.app_state.revocations.is_revoked(&auth_token) {
+            return Err(AuthError::Revoked);
+        }
+        Ok(auth_token)
+    }
+
+    /// The policy named by the token, falling back to `default_policy`.
+    fn subscribe_policy(&self, auth_token: &AuthToken) -> Result<Option<SubscribePolicy>, Status> {
+        let grpc = &self.app_state.settings.grpc;
+        let Some(name) = auth_token
+            .grpc_policy
+            .as_ref()
+            .or(grpc.default_policy.as_ref())
+        else {
+            return Ok(None);
+        };
+        grpc.policies
+            .get(name)
+            .cloned()
+            .map(Some)
+            .ok_or_else(|| Status::permission_denied(format!("unknown gRPC policy '{}'", name)))
+    }
+}
+
+impl Service<Request<Body>> for GeyserProxy {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let proxy = self.clone();
+        Box::pin(async move { Ok(proxy.handle(request).await) })
+    }
+}
+
+/// Forwards one authenticated call upstream and streams the answer back.
+struct Relay {
+    proxy: GeyserProxy,
+    path: PathAndQuery,
+    auth_token: Arc<AuthToken>,
+    request_id: String,
+    policy: Option<SubscribePolicy>,
+}
+
+impl StreamingService<Bytes> for Relay {
+    type Response = Bytes;
+    type ResponseStream = BoxStream<'static, Result<Bytes, Status>>;
+    type Future = BoxFuture<tonic::Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Streaming<Bytes>>) -> Self::Future {
+        let GeyserProxy {
+            app_state,
+            mut upstream,
+            upstream_token,
+        } = self.proxy.clone();
+        let path = self.path.clone();
+        let auth_token = self.auth_token.clone();
+        let user = auth_token.user.clone();
+        let request_id = self.request_id.clone();
+        let policy = self
+            .policy
+            .clone()
+            .filter(|_| self.path.path() == SUBSCRIBE_PATH);
+
+        Box::pin(async move {
+            let start_time = Instant::now();
+            let (metadata, _, messages) = request.into_parts();
+            let metadata = upstream_metadata(metadata, upstream_token);
+            // The client stream ends at its first error
+            let mut messages = messages
+                .take_while(|message| ready(message.is_ok()))
+                .filter_map(|message| ready(message.ok()))
+                .boxed();
+
+            let mut violation = None;
+            if let Some(policy) = policy {
+                // The first request is checked before anything is sent upstream
+                let first = messages.next().await;
+                if let Some(first) = &first
+                    && let Err(status) = policy::check_message(&policy, first)
+                {
+                    log_policy_violation(&user, &status, &request_id);
+                    return Err(status);
+                }
+                // Later requests replace the filters, so they are checked too; a violation
+                // ends the call with its status
+                let (violation_tx, violation_rx) = oneshot::channel();
+                let mut violation_tx = Some(violation_tx);
+                let (user, request_id) = (user.clone(), request_id.clone());
+                let rest = messages.scan((), move |_, message| {
+                    ready(match policy::check_message(&policy, &message) {
+                        Ok(()) => Some(message),
+                        Err(status) => {
+                            log_policy_violation(&user, &status, &request_id);
+                            if let Some(violation_tx) = violation_tx.take() {
+                                let _ = violation_tx.send(status);
+                            }
+                            None
+                        }
+                    })
+                });
+                messages = stream::iter(first).chain(rest).boxed();
+                violation = Some(violation_rx);
+            }
+            let request = tonic::Request::from_parts(metadata, Extensions::default(), messages);
+
+            upstream
+                .ready()
+                .await
+                .map_err(|err| Status::unavailable(format!("upstream unavailable: {}", err)))?;
+            let response = upstream.streaming(request, path.clone(), BytesCodec).await;
+
+            let method = grpc_method(&path);
+            let duration = start_time.elapsed().as_secs_f64() * 1000.0;
+            trace!(
+                event = "grpc_request_forwarded",
+                user = user,
+                duration = duration,
+                backend_url = app_state.settings.backend.yellowstone_grpc_url,
+                status = match &response {
+                    Ok(_) => "ok".to_string(),
+                    Err(status) => format!("{:?}", status.code()),
+                },
+                request_id = request_id
+            );
+            app_state.update_rpc_method_state(&user, method, duration);
+            Ok(response?.map(|responses| {
+                let responses = match violation {
+                    Some(violation_rx) => {
+                        let violation = stream::once(violation_rx)
+                            .filter_map(|violation| ready(violation.ok().map(Err)));
+                        stream::select(responses, violation).boxed()
+                    }
+                    None => responses.boxed(),
+                };
+                until_revoked(responses, app_state, auth_token)
+            }))
+        })
+    }
+}
+
+/// Ends `responses` with an `Unauthenticated` status once the token is revoked, so open
+/// Subscribe streams do not outlive it.
+fn until_revoked(
+    responses: BoxStream<'static, Result<Bytes, Status>>,
+    app_state: Arc<AppState>,
+    auth_token: Arc<AuthToken>,
+) -> BoxStream<'static, Result<Bytes, Status>> {
+    let revoked = stream::once(async move {
+        let mut revocations = app_state.revocations.watch();
+        loop {
+            if app_state.revocations.is_revoked(&auth_token) {
+                return Some(Err(Status::unauthenticated(AuthError::Revoked.to_string())));
+            }
+            if revocations.changed().await.is_err() {
+                return pending().await;
+            }
+        }
+    });
+    // The `None` marking the end of the responses ends the merged stream as well
+    stream::select(
+        responses.map(Some).chain(stream::once(ready(None))),
+        revoked,
+    )
+    .take_while(|response| ready(response.is_some()))
+    .filter_map(ready)
+    .boxed()
+}
+
+/// The method name of a gRPC path, e.g. `Subscribe` for `/geyser.Geyser/Subscribe`.
+fn grpc_method(path: &PathAndQuery) -> &str {
+    path.path().rsplit('/').next().unwrap_or("unknown")
+}
+
+fn log_policy_violation(user: &str, status: &Status, request_id: &str) {
+    trace!(
+        event = "grpc_policy_violation",
+        user = user,
+        reason = status.message(),
+        request_id = request_id
+    );
+}
+
+/// Swaps the client's Sentrix token for the upstream one.
+fn upstream_metadata(
+    mut metadata: MetadataMap,
+    upstream_token: Option<AsciiMetadataValue>,
+) -> MetadataMap {
+    metadata.remove(TOKEN_METADATA_KEY);
+    if let Some(upstream_token) = upstream_token {
+        metadata.insert(TOKEN_METADATA_KEY, upstream_token);
+    }
+    metadata
+}
+
+pub async fn serve_grpc(app_state: Arc<AppState>) {
+    let port = app_state.settings.grpc.port;
+    let proxy = GeyserProxy::new(app_state).unwrap_or_else(|err| {
+        eprintln!("Error configuring Yellowstone gRPC proxy: {}", err);
+        std::process::exit(1);
+    });
+
+    println!("🚀 Yellowstone gRPC proxy listening at 0.0.0.0:{}", port);
+    Server::builder()
+        .add_service(proxy)
+        .serve(([0, 0, 0, 0], port).into())
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("Error starting gRPC server: {}", err);
+            std::process::exit(1);
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::token::generate_token;
+    use crate::config::Settings;
+    use crate::grpc::geyser::SubscribeRequest;
+    use prost::Message;
+    use tonic::transport::server::TcpIncoming;
+
+    /// Stub upstream answering every call with the `x-token` it received.
+    #[derive(Clone)]
+    struct EchoToken;
+
+    impl NamedService for EchoToken {
+        const NAME: &'static str = "geyser.Geyser";
+    }
+
+    impl Service<Request<Body>> for EchoToken {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: Request<Body>) -> Self::Future {
+            Box::pin(async move {
+                let mut server = tonic::server::Grpc::new(BytesCodec);
+                Ok(server.streaming(EchoToken, request).await)
+            })
+        }
+    }
+
+    impl StreamingService<Bytes> for EchoToken {
+        type Response = Bytes;
+        type ResponseStream = futures::stream::Iter<std::vec::IntoIter<Result<Bytes, Status>>>;
+        type Future = BoxFuture<tonic::Response<Self::ResponseStream>, Status>;
+
+        fn call(&mut self, request: tonic::Request<Streaming<Bytes>>) -> Self::Future {
+            let token = request
+                .metadata()
+                .get(TOKEN_METADATA_KEY)
+                .map(|token| Bytes::copy_from_slice(token.as_bytes()))
+                .unwrap_or_default();
+            Box::pin(
+                async move { Ok(tonic::Response::new(futures::stream::iter(vec![Ok(token)]))) },
+            )
+        }
+    }
+
+    async fn spawn_server<S>(service: S) -> String
+    where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+            + NamedService
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        S::Future: Send + 'static,
+    {
+        let incoming = TcpIncoming::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = incoming.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming(incoming),
+        );
+        format!("http://{}", addr)
+    }
+
+    async fn call(
+        proxy_url: &str,
+        token: &str,
+        path: &'static str,
+        message: Bytes,
+    ) -> Result<Bytes, Status> {
+        let channel = Endpoint::from_shared(proxy_url.to_string())
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = Grpc::new(channel);
+        let mut request = tonic::Request::new(futures::stream::iter(vec![message]));
+        request
+            .metadata_mut()
+            .insert(TOKEN_METADATA_KEY, token.parse().unwrap());
+        client.ready().await.unwrap();
+        let mut response = client
+            .streaming(request, PathAndQuery::from_static(path), BytesCodec)
+            .await?
+            .into_inner();
+        Ok(response.message().await?.unwrap_or_default())
+    }
+
+    async fn spawn_proxy(grpc_config: &str) -> (String, String) {
+        let upstream_url = spawn_server(EchoToken).await;
+        let mut settings = Settings::for_test(grpc_config);
+        settings.backend.yellowstone_grpc_url = upstream_url;
+        settings.backend.yellowstone_grpc_token = "upstream-secret".to_string();
+        let token = generate_token(&settings.app.secret_key, "tester", 100, 60);
+        let app_state = Arc::new(AppState::new(&settings));
+        let proxy_url = spawn_server(GeyserProxy::new(app_state).unwrap()).await;
+        (proxy_url, token)
+    }
+
+    #[tokio::test]
+    async fn test_relay_swaps_token() {
+        let (proxy_url, token) = spawn_proxy("").await;
+        let ping = || Bytes::from_static(b"ping");
+
+        let response = call(&proxy_url, &token, "/geyser.Geyser/Ping", ping()).await;
+        assert_eq!(response.unwrap(), "upstream-secret");
+
+        let status = call(&proxy_url, "bogus", "/geyser.Geyser/Ping", ping())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn test_revocation_ends_stream() {
+        let app_state = Arc::new(AppState::new(&Settings::for_test("")));
+        let auth_token: AuthToken =
+            serde_json::from_str(r#"{"user":"tester","exp":0,"qps":1}"#).unwrap();
+        let mut responses = until_revoked(
+            stream::iter([Ok(Bytes::from_static(b"update"))])
+                .chain(stream::pending())
+                .boxed(),
+            app_state.clone(),
+            Arc::new(auth_token),
+        );
+        assert_eq!(responses.next().await.unwrap().unwrap(), "update");
+
+        app_state
+            .revocations
+            .update(|list| {
+                list.users.insert("tester".to_string());
+            })
+            .unwrap();
+        let status = responses.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        // A stream that ends on its own is not held open
+        let responses = until_revoked(
+            stream::empty().boxed(),
+            app_state,
+            Arc::new(serde_json::from_str(r#"{"user":"other","exp":0,"qps":1}"#).unwrap()),
+        );
+        assert_eq!(responses.count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_policy() {
+        let (proxy_url, token) = spawn_proxy(
+            r#"
+            [grpc]
+            default_policy = "no-blocks"
+
+            [grpc.policies.no-blocks]
+            allow_blocks = false
+            "#,
+        )
+        .await;
+
+        let mut request = SubscribeRequest::default();
+        request.accounts.insert("a".into(), Default::default());
+        let message = Bytes::from(request.encode_to_vec());
+        let response = call(&proxy_url, &token, SUBSCRIBE_PATH, message).await;
+        assert_eq!(response.unwrap(), "upstream-secret");
+
+        request.blocks.insert("all".into(), Default::default());
+        let message = Bytes::from(request.encode_to_vec());
+        let status = call(&proxy_url, &token, SUBSCRIBE_PATH, message)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::limits::rate_limit::RateLimitState;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A QPS and concurrency cap shared by many callers, used for the gateway as a whole
+/// and for each upstream. Callers may only fill their priority's share of it, so lower
+/// tiers are turned away first as load approaches the cap.
+pub struct Ceiling {
+    max_qps: Option<u32>,
+    max_in_flight: Option<usize>,
+    rate_limit_state: Mutex<RateLimitState>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Ceiling {
+    pub fn new(max_qps: Option<u32>, max_in_flight: Option<usize>) -> Self {
+        Ceiling {
+            max_qps,
+            max_in_flight,
+            rate_limit_state: Mutex::new(RateLimitState::new(Instant::now())),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Charges `cost` requests against the QPS cap. A caller with a smaller share gets a
+    /// smaller burst, so it is refused while the backlog left by others is still high.
+    pub fn try_acquire(&self, cost: u32, share: f64) -> bool {
+        let Some(max_qps) = self.max_qps else {
+            return true;
+        };
+        let burst = ((max_qps as f64 * share) as u32).max(1);
+        self.rate_limit_state
+            .lock()
+            .unwrap()
+            .try_acquire(Instant::now(), cost, max_qps, burst)
+    }
+
+    /// Takes a concurrency slot, released when the guard is dropped.
+    pub fn try_enter(&self, share: f64) -> Option<CeilingGuard> {
+        let allowed = self
+            .max_in_flight
+            .map_or(usize::MAX, |max| ((max as f64 * share) as usize).max(1));
+        self.in_flight
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |in_flight| {
+                (in_flight < allowed).then_some(in_flight + 1)
+            })
+            .ok()?;
+        Some(CeilingGuard {
+            in_flight: self.in_flight.clone(),
+        })
+    }
+}
+
+pub struct CeilingGuard {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for CeilingGuard {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight_shedding() {
+        let ceiling = Ceiling::new(None, Some(10));
+        let guards: Vec<_> = (0..7).map(|_| ceiling.try_enter(0.7).unwrap()).collect();
+        // Low priority is shed at 70% while high priority still gets the rest
+        assert!(ceiling.try_enter(0.7).is_none());
+        let high: Vec<_> = (0..3).map(|_| ceiling.try_enter(1.0).unwrap()).collect();
+        assert!(ceiling.try_enter(1.0).is_none());
+        drop(guards);
+        drop(high);
+        assert!(ceiling.try_enter(0.7).is_some());
+    }
+
+    #[test]
+    fn test_qps_shedding() {
+        let ceiling = Ceiling::new(Some(100), None);
+        assert!((0..50).all(|_| ceiling.try_acquire(1, 0.5)));
+        assert!(!ceiling.try_acquire(1, 0.5));
+        assert!((0..50).all(|_| ceiling.try_acquire(1, 1.0)));
+        assert!(!ceiling.try_acquire(1, 1.0));
+        assert!(Ceiling::new(None, None).try_acquire(1_000, 0.1));
+    }
+}
@@ -0,0 +1,2 @@
+pub mod ceiling;
+pub mod rate_limit;
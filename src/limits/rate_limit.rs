@@ -0,0 +1,177 @@
+use std::time::{Duration, Instant};
+
+/// GCRA rate limiter state: a user may run at most `burst` credits ahead of their `qps` schedule.
+#[derive(Clone)]
+pub struct RateLimitState {
+    pub theoretical_arrival_time: Instant, // When the user's schedule would next be idle
+}
+
+impl RateLimitState {
+    pub fn new(now: Instant) -> Self {
+        RateLimitState {
+            theoretical_arrival_time: now,
+        }
+    }
+
+    /// Charges `cost` credits. A cost above `burst` is charged as `burst`, so an expensive
+    /// request waits for the full burst to be available instead of never being allowed.
+    pub fn try_acquire(&mut self, now: Instant, cost: u32, qps: u32, burst: u32) -> bool {
+        if qps == 0 || burst == 0 {
+            return false;
+        }
+        let cost = cost.min(burst);
+        let emission_interval = Duration::from_secs_f64(1.0 / qps as f64);
+        let arrival_time = self.theoretical_arrival_time.max(now) + emission_interval * cost;
+        if arrival_time.duration_since(now) > emission_interval * burst {
+            return false;
+        }
+        self.theoretical_arrival_time = arrival_time;
+        true
+    }
+
+    /// Gives back credits charged by `try_acquire` for a request that was turned away later.
+    pub fn refund(&mut self, now: Instant, cost: u32, qps: u32, burst: u32) {
+        if qps == 0 || burst == 0 {
+            return;
+        }
+        let emission_interval = Duration::from_secs_f64(1.0 / qps as f64);
+        let refunded = self
+            .theoretical_arrival_time
+            .checked_sub(emission_interval * cost.min(burst))
+            .unwrap_or(now);
+        self.theoretical_arrival_time = refunded.max(now);
+    }
+
+    /// Remaining capacity as seen at `now`, in credits and whole seconds.
+    pub fn status(&self, now: Instant, qps: u32, burst: u32) -> RateLimitStatus {
+        if qps == 0 || burst == 0 {
+            return RateLimitStatus {
+                limit: burst,
+                remaining: 0,
+                reset_secs: 0,
+                retry_after_secs: 0,
+            };
+        }
+        let emission_interval = 1.0 / qps as f64;
+        let capacity = emission_interval * burst as f64;
+        let backlog = self
+            .theoretical_arrival_time
+            .saturating_duration_since(now)
+            .as_secs_f64();
+        // The epsilon absorbs rounding when the backlog is a whole number of credits
+        let remaining = ((capacity - backlog) / emission_interval + 1e-9).floor();
+        let retry_after = backlog + emission_interval - capacity - 1e-9;
+        RateLimitStatus {
+            limit: burst,
+            remaining: remaining.max(0.0) as u32,
+            reset_secs: backlog.ceil() as u64,
+            retry_after_secs: retry_after.max(0.0).ceil() as u64,
+        }
+    }
+}
+
+/// What the `RateLimit-*` and `Retry-After` response headers report.
+#[derive(Debug, PartialEq)]
+pub struct RateLimitStatus {
+    pub limit: u32,            // Burst capacity in credits
+    pub remaining: u32,        // Credits that can be spent right now
+    pub reset_secs: u64,       // Until the full burst is available again
+    pub retry_after_secs: u64, // Until at least one credit is available
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_burst() {
+        let start = Instant::now();
+        let mut state = RateLimitState::new(start);
+        for _ in 0..5 {
+            assert!(state.try_acquire(start, 1, 10, 5));
+        }
+        assert!(!state.try_acquire(start, 1, 10, 5));
+
+        // One request's worth of capacity comes back every 100ms
+        let later = start + Duration::from_millis(100);
+        assert!(state.try_acquire(later, 1, 10, 5));
+        assert!(!state.try_acquire(later, 1, 10, 5));
+    }
+
+    #[test]
+    fn test_rate_limit_cost_above_burst() {
+        let start = Instant::now();
+        let mut state = RateLimitState::new(start);
+        // Charged as the whole burst, so it needs an idle schedule
+        assert!(state.try_acquire(start, 100, 50, 50));
+        assert!(!state.try_acquire(start, 1, 50, 50));
+        let later = start + Duration::from_millis(500);
+        assert!(!state.try_acquire(later, 100, 50, 50));
+        assert!(state.try_acquire(start + Duration::from_secs(1), 100, 50, 50));
+    }
+
+    #[test]
+    fn test_rate_limit_refund() {
+        let start = Instant::now();
+        let mut state = RateLimitState::new(start);
+        assert!(state.try_acquire(start, 60, 100, 100));
+        state.refund(start, 60, 100, 100);
+        assert!(state.try_acquire(start, 100, 100, 100));
+        // Never back past an idle schedule
+        state.refund(start, 1000, 100, 100);
+        assert_eq!(state.status(start, 100, 100).remaining, 100);
+    }
+
+    #[test]
+    fn test_rate_limit_no_window_boundary_burst() {
+        let start = Instant::now();
+        let mut state = RateLimitState::new(start);
+        for _ in 0..10 {
+            assert!(state.try_acquire(start, 1, 10, 10));
+        }
+        // A fixed window would allow another 10 right after the boundary
+        let later = start + Duration::from_millis(1001);
+        let allowed = (0..20)
+            .filter(|_| state.try_acquire(later, 1, 10, 10))
+            .count();
+        assert_eq!(allowed, 10);
+        assert!(!state.try_acquire(start, 1, 0, 10));
+    }
+
+    #[test]
+    fn test_rate_limit_cost() {
+        let start = Instant::now();
+        let mut state = RateLimitState::new(start);
+        assert!(state.try_acquire(start, 60, 100, 100));
+        assert!(!state.try_acquire(start, 60, 100, 100));
+        assert!(state.try_acquire(start, 40, 100, 100));
+        // 50 credits come back after half a second
+        let later = start + Duration::from_millis(500);
+        assert!(state.try_acquire(later, 50, 100, 100));
+        assert!(!state.try_acquire(later, 1, 100, 100));
+    }
+
+    #[test]
+    fn test_rate_limit_status() {
+        let start = Instant::now();
+        let mut state = RateLimitState::new(start);
+        let full = RateLimitStatus {
+            limit: 10,
+            remaining: 10,
+            reset_secs: 0,
+            retry_after_secs: 0,
+        };
+        assert_eq!(state.status(start, 5, 10), full);
+
+        for _ in 0..10 {
+            assert!(state.try_acquire(start, 1, 5, 10));
+        }
+        let empty = state.status(start, 5, 10);
+        assert_eq!(empty.remaining, 0);
+        assert_eq!(empty.reset_secs, 2);
+        assert_eq!(empty.retry_after_secs, 1);
+
+        let later = start + Duration::from_millis(400);
+        assert_eq!(state.status(later, 5, 10).remaining, 2);
+    }
+}
@@ -4,6 +4,10 @@ use crate::config::Settings;
 mod app;
 mod auth;
 mod config;
+mod grpc;
+mod limits;
+mod quota;
+mod upstream;
 
 #[tokio::main]
 async fn main() {
@@ -0,0 +1,54 @@
+use rusqlite::{Connection, OptionalExtension, params};
+
+/// SQLite file holding the credits each user has consumed per quota period,
+/// so quotas survive restarts.
+pub struct UsageStore {
+    conn: Connection,
+}
+
+impl UsageStore {
+    /// Opens (or creates) the store at `path`; an empty path keeps usage in memory only.
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = if path.is_empty() {
+            Connection::open_in_memory()?
+        } else {
+            Connection::open(path)?
+        };
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS usage (
+                user TEXT NOT NULL,
+                period TEXT NOT NULL,
+                credits INTEGER NOT NULL,
+                PRIMARY KEY (user, period)
+            )",
+        )?;
+        Ok(UsageStore { conn })
+    }
+
+    pub fn load(&self, user: &str, period: &str) -> rusqlite::Result<u64> {
+        let credits: Option<i64> = self
+            .conn
+            .query_row(
+                "SELECT credits FROM usage WHERE user = ?1 AND period = ?2",
+                params![user, period],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(credits.unwrap_or(0) as u64)
+    }
+
+    /// Writes `(user, period, credits)` rows in a single transaction.
+    pub fn save(&mut self, rows: &[(String, String, u64)]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO usage (user, period, credits) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (user, period) DO UPDATE SET credits = excluded.credits",
+            )?;
+            for (user, period, credits) in rows {
+                stmt.execute(params![user, period, *credits as i64])?;
+            }
+        }
+        tx.commit()
+    }
+}
@@ -0,0 +1,345 @@
+use crate::app::state::AppState;
+use crate::quota::store::UsageStore;
+use chrono::{DateTime, Datelike, Days, NaiveDate, Utc};
+use dashmap::DashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+impl QuotaPeriod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QuotaPeriod::Daily => "daily",
+            QuotaPeriod::Monthly => "monthly",
+        }
+    }
+
+    /// Storage key of the period containing `now`, e.g. "2025-04-15" or "2025-04".
+    fn key(&self, now: DateTime<Utc>) -> String {
+        match self {
+            QuotaPeriod::Daily => now.format("%Y-%m-%d").to_string(),
+            QuotaPeriod::Monthly => now.format("%Y-%m").to_string(),
+        }
+    }
+
+    /// Start of the next period; periods follow UTC calendar days and months.
+    pub fn reset_at(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.date_naive();
+        let next = match self {
+            QuotaPeriod::Daily => today + Days::new(1),
+            QuotaPeriod::Monthly => {
+                let (year, month) = match today.month() {
+                    12 => (today.year() + 1, 1),
+                    month => (today.year(), month + 1),
+                };
+                NaiveDate::from_ymd_opt(year, month, 1).unwrap_or(today)
+            }
+        };
+        next.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc()
+    }
+}
+
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub period: QuotaPeriod,
+    pub quota: u64,
+    pub used: u64,
+    pub reset_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct UserUsage {
+    day: String,
+    daily: u64,
+    month: String,
+    monthly: u64,
+    dirty: bool, // Changed since the last flush
+}
+
+/// Counts the credits each user consumes per day and month. Counters live in memory
+/// and are flushed to the `UsageStore` periodically; they are loaded back from it the
+/// first time a user is seen in a period, by `warm_up` so the store is never queried
+/// on an async worker thread.
+pub struct QuotaTracker {
+    store: Mutex<UsageStore>,
+    usage: DashMap<String, UserUsage>, // user_id -> current periods' usage
+}
+
+impl QuotaTracker {
+    pub fn new(store: UsageStore) -> Self {
+        QuotaTracker {
+            store: Mutex::new(store),
+            usage: DashMap::new(),
+        }
+    }
+
+    /// Loads the user's usage of the current periods from the store, if not in memory yet.
+    /// Call before `consume`; the store queries run on the blocking thread pool.
+    pub async fn warm_up(self: &Arc<Self>, user_id: &str) {
+        if self.is_loaded(user_id, Utc::now()) {
+            return;
+        }
+        let tracker = self.clone();
+        let user_id = user_id.to_string();
+        let _ =
+            tokio::task::spawn_blocking(move || tracker.load_periods(&user_id, Utc::now())).await;
+    }
+
+    fn is_loaded(&self, user_id: &str, now: DateTime<Utc>) -> bool {
+        self.usage.get(user_id).is_some_and(|usage| {
+            usage.day == QuotaPeriod::Daily.key(now) && usage.month == QuotaPeriod::Monthly.key(now)
+        })
+    }
+
+    /// Reads the counters of the periods containing `now` that are not in memory yet,
+    /// without holding the user's map entry during the queries.
+    fn load_periods(&self, user_id: &str, now: DateTime<Utc>) {
+        let day = QuotaPeriod::Daily.key(now);
+        let month = QuotaPeriod::Monthly.key(now);
+        let (load_day, load_month) = match self.usage.get(user_id) {
+            Some(usage) => (usage.day != day, usage.month != month),
+            None => (true, true),
+        };
+        let daily = load_day.then(|| self.load(user_id, &day));
+        let monthly = load_month.then(|| self.load(user_id, &month));
+
+        let mut usage = self.usage.entry(user_id.to_string()).or_default();
+        // Unless another request got there first
+        if let Some(daily) = daily
+            && usage.day != day
+        {
+            usage.daily = daily;
+            usage.day = day;
+        }
+        if let Some(monthly) = monthly
+            && usage.month != month
+        {
+            usage.monthly = monthly;
+            usage.month = month;
+        }
+    }
+
+    /// Charges `cost` credits unless that would exceed one of the quotas. Usage is
+    /// tracked even for users without quotas.
+    pub fn consume(
+        &self,
+        user_id: &str,
+        cost: u32,
+        daily_quota: Option<u64>,
+        monthly_quota: Option<u64>,
+    ) -> Result<(), QuotaExceeded> {
+        self.consume_at(user_id, cost, daily_quota, monthly_quota, Utc::now())
+    }
+
+    fn consume_at(
+        &self,
+        user_id: &str,
+        cost: u32,
+        daily_quota: Option<u64>,
+        monthly_quota: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> Result<(), QuotaExceeded> {
+        if !self.is_loaded(user_id, now) {
+            // Only when a period began since `warm_up`, or it was skipped
+            self.load_periods(user_id, now);
+        }
+        let mut usage = self.usage.entry(user_id.to_string()).or_default();
+        // The entry may have been evicted in between; the store is not queried under it
+        let day = QuotaPeriod::Daily.key(now);
+        if usage.day != day {
+            usage.daily = 0;
+            usage.day = day;
+        }
+        let month = QuotaPeriod::Monthly.key(now);
+        if usage.month != month {
+            usage.monthly = 0;
+            usage.month = month;
+        }
+
+        let cost = cost as u64;
+        for (period, quota, used) in [
+            (QuotaPeriod::Daily, daily_quota, usage.daily),
+            (QuotaPeriod::Monthly, monthly_quota, usage.monthly),
+        ] {
+            if let Some(quota) = quota
+                && used + cost > quota
+            {
+                return Err(QuotaExceeded {
+                    period,
+                    quota,
+                    used,
+                    reset_at: period.reset_at(now),
+                });
+            }
+        }
+        usage.daily += cost;
+        usage.monthly += cost;
+        usage.dirty = true;
+        Ok(())
+    }
+
+    /// Gives back credits charged by `consume` for a request that got no upstream answer.
+    pub fn refund(&self, user_id: &str, cost: u64) {
+        if let Some(mut usage) = self.usage.get_mut(user_id) {
+            usage.daily = usage.daily.saturating_sub(cost);
+            usage.monthly = usage.monthly.saturating_sub(cost);
+            usage.dirty = true;
+        }
+    }
+
+    fn load(&self, user_id: &str, period: &str) -> u64 {
+        let store = self.store.lock().unwrap();
+        store.load(user_id, period).unwrap_or_else(|err| {
+            warn!(
+                event = "quota_load_failed",
+                user = user_id,
+                period = period,
+                error = err.to_string()
+            );
+            0
+        })
+    }
+
+    /// Writes changed counters to the store and forgets users not seen today.
+    pub fn flush(&self, now: DateTime<Utc>) -> rusqlite::Result<()> {
+        let mut rows = Vec::new();
+        for mut usage in self.usage.iter_mut() {
+            if usage.dirty {
+                usage.dirty = false;
+                let user = usage.key().clone();
+                rows.push((user.clone(), usage.day.clone(), usage.daily));
+                rows.push((user, usage.month.clone(), usage.monthly));
+            }
+        }
+        if !rows.is_empty()
+            && let Err(err) = self.store.lock().unwrap().save(&rows)
+        {
+            // Keep the counters marked so the next flush tries again
+            for (user, _, _) in &rows {
+                if let Some(mut usage) = self.usage.get_mut(user) {
+                    usage.dirty = true;
+                }
+            }
+            return Err(err);
+        }
+
+        let today = QuotaPeriod::Daily.key(now);
+        self.usage
+            .retain(|_, usage| usage.dirty || usage.day == today);
+        Ok(())
+    }
+}
+
+pub async fn run_usage_flusher(app_state: Arc<AppState>) {
+    let flush_interval = Duration::from_secs(app_state.settings.quota.flush_interval_secs.max(1));
+    let mut interval = tokio::time::interval(flush_interval);
+    loop {
+        interval.tick().await;
+        let quota = app_state.quota.clone();
+        let result = tokio::task::spawn_blocking(move || quota.flush(Utc::now())).await;
+        if let Ok(Err(err)) = result {
+            warn!(event = "quota_flush_failed", error = err.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        timestamp.parse().unwrap()
+    }
+
+    #[test]
+    fn test_reset_at() {
+        let now = at("2025-12-31T18:30:00Z");
+        assert_eq!(QuotaPeriod::Daily.reset_at(now), at("2026-01-01T00:00:00Z"));
+        assert_eq!(
+            QuotaPeriod::Monthly.reset_at(now),
+            at("2026-01-01T00:00:00Z")
+        );
+        let now = at("2025-04-15T10:00:00Z");
+        assert_eq!(QuotaPeriod::Daily.reset_at(now), at("2025-04-16T00:00:00Z"));
+        assert_eq!(
+            QuotaPeriod::Monthly.reset_at(now),
+            at("2025-05-01T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn test_consume() {
+        let tracker = QuotaTracker::new(UsageStore::open("").unwrap());
+        let now = at("2025-04-15T10:00:00Z");
+        assert!(
+            tracker
+                .consume_at("alice", 60, Some(100), Some(150), now)
+                .is_ok()
+        );
+        let exceeded = tracker
+            .consume_at("alice", 50, Some(100), Some(150), now)
+            .unwrap_err();
+        assert_eq!(exceeded.period, QuotaPeriod::Daily);
+        assert_eq!(exceeded.used, 60);
+        assert!(
+            tracker
+                .consume_at("alice", 40, Some(100), Some(150), now)
+                .is_ok()
+        );
+
+        // The daily quota resets the next day, the monthly one does not
+        let tomorrow = at("2025-04-16T10:00:00Z");
+        let exceeded = tracker
+            .consume_at("alice", 60, Some(100), Some(150), tomorrow)
+            .unwrap_err();
+        assert_eq!(exceeded.period, QuotaPeriod::Monthly);
+        assert_eq!(exceeded.reset_at, at("2025-05-01T00:00:00Z"));
+        assert!(
+            tracker
+                .consume_at("bob", 1000, None, None, tomorrow)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_usage_survives_flush() {
+        let tracker = QuotaTracker::new(UsageStore::open("").unwrap());
+        let now = at("2025-04-15T10:00:00Z");
+        tracker.consume_at("alice", 70, None, None, now).unwrap();
+        // Flushing a day later writes the counters out and evicts the idle user
+        tracker.flush(at("2025-04-16T00:00:01Z")).unwrap();
+        assert!(tracker.usage.is_empty());
+        // Reloaded from the store
+        assert!(
+            tracker
+                .consume_at("alice", 40, Some(100), None, now)
+                .is_err()
+        );
+        assert!(
+            tracker
+                .consume_at("alice", 30, Some(100), None, now)
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warm_up_and_refund() {
+        let tracker = Arc::new(QuotaTracker::new(UsageStore::open("").unwrap()));
+        tracker.consume("alice", 70, None, None).unwrap();
+        tracker.flush(Utc::now() + Days::new(1)).unwrap();
+        assert!(tracker.usage.is_empty());
+
+        tracker.warm_up("alice").await;
+        assert!(tracker.is_loaded("alice", Utc::now()));
+        assert!(tracker.consume("alice", 40, Some(100), None).is_err());
+
+        tracker.refund("alice", 20);
+        assert!(tracker.consume("alice", 40, Some(100), None).is_ok());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::app::state::AppState;
+use crate::config::HealthCheck;
+use crate::upstream::pool::Upstream;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+/// Periodically probes every upstream with `getHealth` and `getSlot`, taking nodes out of
+/// rotation when they fail or lag too far behind the highest slot seen across all upstreams.
+pub async fn run_health_checker(app_state: Arc<AppState>) {
+    let config = app_state.settings.backend.health_check.clone();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    let upstreams: Vec<Arc<Upstream>> = app_state
+        .pools
+        .pools()
+        .iter()
+        .flat_map(|pool| pool.upstreams().iter().cloned())
+        .collect();
+    loop {
+        interval.tick().await;
+        check_upstreams(&app_state.http_client, &upstreams, &config).await;
+    }
+}
+
+/// Probes each distinct upstream URL once and applies the outcome to every pool member
+/// pointing at it.
+async fn check_upstreams(
+    http_client: &reqwest::Client,
+    upstreams: &[Arc<Upstream>],
+    config: &HealthCheck,
+) {
+    let mut by_url: BTreeMap<&str, Vec<&Arc<Upstream>>> = BTreeMap::new();
+    for upstream in upstreams {
+        by_url.entry(&upstream.url).or_default().push(upstream);
+    }
+
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let mut probes = JoinSet::new();
+    for url in by_url.keys() {
+        let http_client = http_client.clone();
+        let url = url.to_string();
+        probes.spawn(async move {
+            let result = probe(&http_client, &url, timeout).await;
+            (url, result)
+        });
+    }
+    let results = probes.join_all().await;
+
+    let highest_slot = results
+        .iter()
+        .filter_map(|(_, result)| result.as_ref().ok())
+        .copied()
+        .max()
+        .unwrap_or(0);
+
+    for (url, result) in results {
+        let members = &by_url[url.as_str()];
+        let outcome = result.and_then(|slot| {
+            members.iter().for_each(|upstream| upstream.set_slot(slot));
+            let lag = highest_slot.saturating_sub(slot);
+            if lag > config.max_slot_lag {
+                Err(format!("{} slots behind", lag))
+            } else {
+                Ok(())
+            }
+        });
+        let changed = members.iter().fold(false, |changed, upstream| {
+            upstream.set_healthy(outcome.is_ok()) | changed
+        });
+        if !changed {
+            continue;
+        }
+        let slot = members[0].slot();
+        match outcome {
+            Ok(()) => info!(event = "upstream_recovered", backend_url = url, slot = slot),
+            Err(reason) => warn!(
+                event = "upstream_ejected",
+                backend_url = url,
+                slot = slot,
+                highest_slot = highest_slot,
+                reason = reason,
+            ),
+        }
+    }
+}
+
+async fn probe(http_client: &reqwest::Client, url: &str, timeout: Duration) -> Result<u64, String> {
+    let health = rpc_call(http_client, url, "getHealth", timeout).await?;
+    if health.as_str() != Some("ok") {
+        return Err(format!("getHealth returned {}", health));
+    }
+    let slot = rpc_call(http_client, url, "getSlot", timeout).await?;
+    slot.as_u64()
+        .ok_or_else(|| format!("getSlot returned {}", slot))
+}
+
+async fn rpc_call(
+    http_client: &reqwest::Client,
+    url: &str,
+    method: &str,
+    timeout: Duration,
+) -> Result<Value, String> {
+    let response = http_client
+        .post(url)
+        .timeout(timeout)
+        .json(&json!({"jsonrpc": "2.0", "id": 1, "method": method}))
+        .send()
+        .await
+        .map_err(|err| format!("{} failed: {}", method, err))?;
+    let body: Value = response
+        .json()
+        .await
+        .map_err(|err| format!("{} failed: {}", method, err))?;
+    if let Some(error) = body.get("error") {
+        return Err(format!("{} failed: {}", method, error));
+    }
+    body.get("result")
+        .cloned()
+        .ok_or_else(|| format!("{} returned no result", method))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RpcUpstream;
+    use axum::{Json, Router, routing::post};
+
+    /// Serves a node that is healthy and reports `slot`.
+    async fn spawn_node(slot: u64) -> String {
+        let app = Router::new().route(
+            "/",
+            post(move |Json(request): Json<Value>| async move {
+                let result = match request["method"].as_str() {
+                    Some("getHealth") => json!("ok"),
+                    _ => json!(slot),
+                };
+                Json(json!({"jsonrpc": "2.0", "id": 1, "result": result}))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    fn upstream(url: &str) -> Arc<Upstream> {
+        Arc::new(Upstream::new(&RpcUpstream {
+            url: url.to_string(),
+            weight: 1,
+            max_qps: None,
+            max_in_flight: None,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_lag_measured_across_pools() {
+        let ahead = spawn_node(1000).await;
+        let behind = spawn_node(900).await;
+        // The lagging node sits in a pool of its own and in the pool of the node ahead
+        let upstreams = vec![upstream(&ahead), upstream(&behind), upstream(&behind)];
+        let config = HealthCheck::default();
+        check_upstreams(&reqwest::Client::new(), &upstreams, &config).await;
+
+        assert!(upstreams[0].is_healthy());
+        assert!(!upstreams[1].is_healthy());
+        assert!(!upstreams[2].is_healthy());
+        assert_eq!(upstreams[2].slot(), 900);
+    }
+}
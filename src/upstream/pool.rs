@@ -0,0 +1,286 @@
+use crate::app::metrics::backend_label;
+use crate::config::{LoadBalanceStrategy, RpcUpstream};
+use crate::limits::ceiling::Ceiling;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Weight given to the newest sample in the latency moving average
+const EWMA_ALPHA: f64 = 0.3;
+
+pub struct Upstream {
+    pub url: String,
+    pub label: String, // The URL without credentials, path or query, for metrics
+    pub weight: u32,
+    pub ceiling: Ceiling, // Configured max_qps / max_in_flight
+    in_flight: AtomicUsize,
+    latency_ewma: AtomicU64, // f64 bits, in milliseconds; 0 until the first sample
+    healthy: AtomicBool,
+    slot: AtomicU64, // Last slot reported by the health checker
+}
+
+impl Upstream {
+    pub fn new(config: &RpcUpstream) -> Self {
+        Upstream {
+            url: config.url.clone(),
+            label: backend_label(&config.url),
+            weight: config.weight,
+            ceiling: Ceiling::new(config.max_qps, config.max_in_flight),
+            in_flight: AtomicUsize::new(0),
+            latency_ewma: AtomicU64::new(0f64.to_bits()),
+            healthy: AtomicBool::new(true),
+            slot: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Updates the health state, returning true if it changed.
+    pub fn set_healthy(&self, healthy: bool) -> bool {
+        self.healthy.swap(healthy, Ordering::Relaxed) != healthy
+    }
+
+    pub fn slot(&self) -> u64 {
+        self.slot.load(Ordering::Relaxed)
+    }
+
+    pub fn set_slot(&self, slot: u64) {
+        self.slot.store(slot, Ordering::Relaxed);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn latency_ewma(&self) -> f64 {
+        f64::from_bits(self.latency_ewma.load(Ordering::Relaxed))
+    }
+
+    pub fn record_latency(&self, response_time: f64) {
+        let _ = self
+            .latency_ewma
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let current = f64::from_bits(bits);
+                let updated = if current == 0.0 {
+                    response_time
+                } else {
+                    current + EWMA_ALPHA * (response_time - current)
+                };
+                Some(updated.to_bits())
+            });
+    }
+
+    /// Marks a request as in flight until the returned guard is dropped.
+    pub fn start_request(self: &Arc<Self>) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard {
+            upstream: self.clone(),
+        }
+    }
+}
+
+pub struct InFlightGuard {
+    upstream: Arc<Upstream>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.upstream.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct UpstreamPool {
+    pub name: String,
+    strategy: LoadBalanceStrategy,
+    upstreams: Vec<Arc<Upstream>>,
+    next: AtomicUsize,                // Round-robin cursor
+    current_weights: Mutex<Vec<i64>>, // Smooth weighted round-robin state
+}
+
+impl UpstreamPool {
+    pub fn new(name: &str, strategy: LoadBalanceStrategy, configs: &[RpcUpstream]) -> Self {
+        UpstreamPool {
+            name: name.to_string(),
+            strategy,
+            upstreams: configs.iter().map(|c| Arc::new(Upstream::new(c))).collect(),
+            next: AtomicUsize::new(0),
+            current_weights: Mutex::new(vec![0; configs.len()]),
+        }
+    }
+
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    /// Picks an upstream according to the pool's strategy, preferring healthy upstreams
+    /// not listed in `exclude` (e.g. ones a request has already failed on). Falls back to
+    /// any healthy upstream, then to the whole pool if none are healthy.
+    pub fn select(&self, exclude: &[Arc<Upstream>]) -> Option<Arc<Upstream>> {
+        let healthy: Vec<usize> = (0..self.upstreams.len())
+            .filter(|&i| self.upstreams[i].is_healthy())
+            .collect();
+        let mut candidates: Vec<usize> = healthy
+            .iter()
+            .copied()
+            .filter(|&i| !exclude.iter().any(|e| Arc::ptr_eq(e, &self.upstreams[i])))
+            .collect();
+        if candidates.is_empty() {
+            candidates = healthy;
+        }
+        if candidates.is_empty() {
+            candidates = (0..self.upstreams.len()).collect();
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = match self.strategy {
+            LoadBalanceStrategy::RoundRobin => self.select_round_robin(&candidates),
+            LoadBalanceStrategy::Weighted => self.select_weighted(&candidates),
+            LoadBalanceStrategy::LeastInFlight => self.select_least_in_flight(&candidates),
+            LoadBalanceStrategy::LatencyEwma => self.select_latency_ewma(&candidates),
+        };
+        Some(self.upstreams[index].clone())
+    }
+
+    fn select_round_robin(&self, candidates: &[usize]) -> usize {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        candidates[n % candidates.len()]
+    }
+
+    fn select_weighted(&self, candidates: &[usize]) -> usize {
+        // Smooth weighted round-robin (as in nginx): spreads picks evenly instead of in bursts
+        let mut current_weights = self.current_weights.lock().unwrap();
+        let mut total = 0i64;
+        let mut best = candidates[0];
+        for &i in candidates {
+            let weight = self.upstreams[i].weight as i64;
+            current_weights[i] += weight;
+            total += weight;
+            if current_weights[i] > current_weights[best] {
+                best = i;
+            }
+        }
+        current_weights[best] -= total;
+        best
+    }
+
+    fn select_least_in_flight(&self, candidates: &[usize]) -> usize {
+        // Start from a rotating offset so ties do not always land on the first upstream
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..candidates.len())
+            .map(|k| candidates[(offset + k) % candidates.len()])
+            .min_by_key(|&i| self.upstreams[i].in_flight())
+            .unwrap()
+    }
+
+    fn select_latency_ewma(&self, candidates: &[usize]) -> usize {
+        // Expected cost of a new request: observed latency scaled by queued work.
+        // Upstreams without samples yet score 0 so they get probed.
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..candidates.len())
+            .map(|k| candidates[(offset + k) % candidates.len()])
+            .min_by(|&a, &b| self.cost(a).total_cmp(&self.cost(b)))
+            .unwrap()
+    }
+
+    fn cost(&self, index: usize) -> f64 {
+        let upstream = &self.upstreams[index];
+        upstream.latency_ewma() * (upstream.in_flight() + 1) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: LoadBalanceStrategy, weights: &[u32]) -> UpstreamPool {
+        let configs: Vec<RpcUpstream> = weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| RpcUpstream {
+                url: format!("http://node-{}", i),
+                weight,
+                max_qps: None,
+                max_in_flight: None,
+            })
+            .collect();
+        UpstreamPool::new("test", strategy, &configs)
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = pool(LoadBalanceStrategy::RoundRobin, &[1, 1, 1]);
+        let picks: Vec<String> = (0..6)
+            .map(|_| pool.select(&[]).unwrap().url.clone())
+            .collect();
+        assert_eq!(picks[0..3], picks[3..6]);
+        assert_ne!(picks[0], picks[1]);
+        assert_ne!(picks[1], picks[2]);
+    }
+
+    #[test]
+    fn test_weighted() {
+        let pool = pool(LoadBalanceStrategy::Weighted, &[5, 1, 1]);
+        let picks: Vec<String> = (0..7)
+            .map(|_| pool.select(&[]).unwrap().url.clone())
+            .collect();
+        assert_eq!(picks.iter().filter(|u| *u == "http://node-0").count(), 5);
+        assert_eq!(picks.iter().filter(|u| *u == "http://node-1").count(), 1);
+        assert_eq!(picks.iter().filter(|u| *u == "http://node-2").count(), 1);
+    }
+
+    #[test]
+    fn test_least_in_flight() {
+        let pool = pool(LoadBalanceStrategy::LeastInFlight, &[1, 1]);
+        let _busy = pool.upstreams()[0].start_request();
+        for _ in 0..4 {
+            assert_eq!(pool.select(&[]).unwrap().url, "http://node-1");
+        }
+    }
+
+    #[test]
+    fn test_latency_ewma() {
+        let pool = pool(LoadBalanceStrategy::LatencyEwma, &[1, 1]);
+        pool.upstreams()[0].record_latency(50.0);
+        pool.upstreams()[1].record_latency(10.0);
+        assert_eq!(pool.select(&[]).unwrap().url, "http://node-1");
+
+        pool.upstreams()[1].record_latency(200.0);
+        assert!(pool.upstreams()[1].latency_ewma() > 50.0);
+        assert_eq!(pool.select(&[]).unwrap().url, "http://node-0");
+    }
+
+    #[test]
+    fn test_unhealthy_upstreams_are_skipped() {
+        let pool = pool(LoadBalanceStrategy::RoundRobin, &[1, 1, 1]);
+        pool.upstreams()[1].set_healthy(false);
+        for _ in 0..6 {
+            assert_ne!(pool.select(&[]).unwrap().url, "http://node-1");
+        }
+
+        // With every upstream down, fall back to the whole pool rather than failing
+        pool.upstreams()[0].set_healthy(false);
+        pool.upstreams()[2].set_healthy(false);
+        assert!(pool.select(&[]).is_some());
+    }
+
+    #[test]
+    fn test_select_with_exclusions() {
+        let pool = pool(LoadBalanceStrategy::RoundRobin, &[1, 1]);
+        let tried = vec![pool.upstreams()[0].clone()];
+        for _ in 0..4 {
+            assert_eq!(pool.select(&tried).unwrap().url, "http://node-1");
+        }
+
+        // Nothing left to fail over to: retry on an upstream that was already tried
+        let tried = pool.upstreams().to_vec();
+        assert!(pool.select(&tried).is_some());
+    }
+
+    #[test]
+    fn test_empty_pool() {
+        let pool = pool(LoadBalanceStrategy::RoundRobin, &[]);
+        assert!(pool.select(&[]).is_none());
+    }
+}
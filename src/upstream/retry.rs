@@ -0,0 +1,72 @@
+use crate::config::Retry;
+use reqwest::StatusCode;
+use std::time::Duration;
+
+// Read-only methods that do not follow the `get*` naming convention
+const READ_ONLY_METHODS: &[&str] = &[
+    "isBlockhashValid",
+    "minimumLedgerSlot",
+    "simulateTransaction",
+];
+
+pub fn is_read_only(rpc_method: &str) -> bool {
+    rpc_method.starts_with("get") || READ_ONLY_METHODS.contains(&rpc_method)
+}
+
+/// A request may be retried only if every method in it is read-only, unless
+/// retrying write methods (e.g. `sendTransaction`) has been explicitly enabled.
+pub fn is_retryable(config: &Retry, rpc_methods: &[&str]) -> bool {
+    config.max_retries > 0
+        && (config.retry_write_methods || rpc_methods.iter().all(|m| is_read_only(m)))
+}
+
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Delay before the given retry attempt (1-based), growing exponentially.
+pub fn backoff(config: &Retry, attempt: u32) -> Duration {
+    let delay = config
+        .backoff_ms
+        .saturating_mul(1u64 << (attempt.saturating_sub(1)).min(16));
+    Duration::from_millis(delay.min(config.max_backoff_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_retryable() {
+        let config = Retry::default();
+        assert!(is_retryable(&config, &["getAccountInfo", "getSlot"]));
+        assert!(is_retryable(&config, &["simulateTransaction"]));
+        assert!(!is_retryable(&config, &["getBalance", "sendTransaction"]));
+        assert!(!is_retryable(&config, &["unknown"]));
+
+        let config = Retry {
+            retry_write_methods: true,
+            ..Retry::default()
+        };
+        assert!(is_retryable(&config, &["sendTransaction"]));
+
+        let config = Retry {
+            max_retries: 0,
+            ..Retry::default()
+        };
+        assert!(!is_retryable(&config, &["getSlot"]));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = Retry {
+            backoff_ms: 100,
+            max_backoff_ms: 300,
+            ..Retry::default()
+        };
+        assert_eq!(backoff(&config, 1), Duration::from_millis(100));
+        assert_eq!(backoff(&config, 2), Duration::from_millis(200));
+        assert_eq!(backoff(&config, 3), Duration::from_millis(300));
+        assert_eq!(backoff(&config, 40), Duration::from_millis(300));
+    }
+}
@@ -0,0 +1,178 @@
+use crate::config::Backend;
+use crate::upstream::pool::UpstreamPool;
+use std::sync::Arc;
+
+pub const DEFAULT_POOL: &str = "default";
+
+/// Maps RPC methods to upstream pools. The first route with a matching pattern wins;
+/// methods matching no route go to the default pool built from `[[backend.rpc]]`.
+pub struct PoolRouter {
+    default_pool: Arc<UpstreamPool>,
+    pools: Vec<Arc<UpstreamPool>>, // All pools, including the default one
+    routes: Vec<(Vec<String>, Arc<UpstreamPool>)>,
+}
+
+impl PoolRouter {
+    pub fn new(backend: &Backend) -> Result<Self, String> {
+        let default_pool = Arc::new(UpstreamPool::new(
+            DEFAULT_POOL,
+            backend.strategy,
+            &backend.rpc_upstreams(),
+        ));
+        if default_pool.upstreams().is_empty() {
+            return Err("no RPC upstream configured in [backend]".to_string());
+        }
+
+        let mut pools = vec![default_pool.clone()];
+        for config in &backend.pools {
+            if pools.iter().any(|pool| pool.name == config.name) {
+                return Err(format!("duplicate backend pool '{}'", config.name));
+            }
+            if config.rpc.is_empty() {
+                return Err(format!("backend pool '{}' has no upstreams", config.name));
+            }
+            pools.push(Arc::new(UpstreamPool::new(
+                &config.name,
+                config.strategy,
+                &config.rpc,
+            )));
+        }
+
+        let mut routes = Vec::with_capacity(backend.routes.len());
+        for route in &backend.routes {
+            let pool = pools
+                .iter()
+                .find(|pool| pool.name == route.pool)
+                .ok_or_else(|| format!("route refers to unknown backend pool '{}'", route.pool))?;
+            routes.push((route.methods.clone(), pool.clone()));
+        }
+
+        Ok(PoolRouter {
+            default_pool,
+            pools,
+            routes,
+        })
+    }
+
+    pub fn pools(&self) -> &[Arc<UpstreamPool>] {
+        &self.pools
+    }
+
+    pub fn route(&self, rpc_method: &str) -> &Arc<UpstreamPool> {
+        self.routes
+            .iter()
+            .find(|(patterns, _)| {
+                patterns
+                    .iter()
+                    .any(|pattern| matches_pattern(pattern, rpc_method))
+            })
+            .map(|(_, pool)| pool)
+            .unwrap_or(&self.default_pool)
+    }
+}
+
+/// Glob match where `*` stands for any (possibly empty) sequence of characters.
+fn matches_pattern(pattern: &str, rpc_method: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = rpc_method.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty(); // No wildcard: exact match
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{LoadBalanceStrategy, PoolConfig, Route, RpcUpstream};
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("getSlot", "getSlot"));
+        assert!(!matches_pattern("getSlot", "getSlotLeader"));
+        assert!(matches_pattern("getSignatures*", "getSignaturesForAddress"));
+        assert!(matches_pattern("getSignatures*", "getSignatures"));
+        assert!(!matches_pattern("getSignatures*", "getSignatureStatuses"));
+        assert!(matches_pattern("*Transaction", "sendTransaction"));
+        assert!(matches_pattern("get*Accounts", "getProgramAccounts"));
+        assert!(!matches_pattern("get*Accounts", "getProgramAccount"));
+        assert!(matches_pattern("*", "anything"));
+    }
+
+    fn pool_config(name: &str) -> PoolConfig {
+        PoolConfig {
+            name: name.to_string(),
+            strategy: LoadBalanceStrategy::RoundRobin,
+            rpc: vec![RpcUpstream {
+                url: format!("http://{}", name),
+                weight: 1,
+                max_qps: None,
+                max_in_flight: None,
+            }],
+        }
+    }
+
+    fn backend(pools: Vec<PoolConfig>, routes: Vec<Route>) -> Backend {
+        Backend {
+            rpc_url: "http://default".to_string(),
+            rpc: vec![],
+            strategy: LoadBalanceStrategy::RoundRobin,
+            pools,
+            routes,
+            health_check: Default::default(),
+            retry: Default::default(),
+            ws_url: String::new(),
+            yellowstone_grpc_url: String::new(),
+            yellowstone_grpc_token: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_route() {
+        let router = PoolRouter::new(&backend(
+            vec![pool_config("heavy"), pool_config("submission")],
+            vec![
+                Route {
+                    methods: vec![
+                        "getProgramAccounts".to_string(),
+                        "getSignatures*".to_string(),
+                    ],
+                    pool: "heavy".to_string(),
+                },
+                Route {
+                    methods: vec!["sendTransaction".to_string()],
+                    pool: "submission".to_string(),
+                },
+            ],
+        ))
+        .unwrap();
+
+        assert_eq!(router.route("getProgramAccounts").name, "heavy");
+        assert_eq!(router.route("getSignaturesForAddress").name, "heavy");
+        assert_eq!(router.route("sendTransaction").name, "submission");
+        assert_eq!(router.route("getSlot").name, DEFAULT_POOL);
+        assert_eq!(router.pools().len(), 3);
+    }
+
+    #[test]
+    fn test_unknown_pool() {
+        let result = PoolRouter::new(&backend(
+            vec![],
+            vec![Route {
+                methods: vec!["getSlot".to_string()],
+                pool: "missing".to_string(),
+            }],
+        ));
+        assert!(result.is_err());
+    }
+}